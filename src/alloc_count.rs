@@ -0,0 +1,47 @@
+// Counts heap allocations, so the debug overlay can show how many each frame makes. Only debug
+// builds install the counting allocator; release builds use the system one and count nothing.
+
+#[cfg(debug_assertions)]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    // Per thread, so a frame's count isn't mixed up with work on other threads. A const
+    // initializer with no destructor never allocates, so the allocator can use it safely.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(debug_assertions)]
+fn count() {
+    // fails only while the thread is being torn down
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+// The system allocator, counting every allocation and reallocation on the way through.
+#[cfg(debug_assertions)]
+pub struct CountingAllocator;
+
+#[cfg(debug_assertions)]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+// Whether allocations are counted at all in this build.
+pub const ENABLED: bool = cfg!(debug_assertions);
+
+// Allocations made on this thread since it started.
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
@@ -0,0 +1,216 @@
+// Grid geometry shared by anything that walks lines or measures ranges on the map. Points are
+// (x, y) tile coordinates, and every function here is pure.
+
+use std::cmp;
+
+// Straight-line (Euclidean) distance between two tiles.
+pub fn distance(from: (i32, i32), to: (i32, i32)) -> f32 {
+    let (dx, dy) = ((to.0 - from.0) as f32, (to.1 - from.1) as f32);
+    (dx * dx + dy * dy).sqrt()
+}
+
+// Distance counting diagonal steps as one, i.e. the number of moves with 8-way movement.
+#[allow(dead_code)]
+pub fn chebyshev_distance(from: (i32, i32), to: (i32, i32)) -> i32 {
+    cmp::max((to.0 - from.0).abs(), (to.1 - from.1).abs())
+}
+
+// The tiles on a Bresenham line from `from` to `to`, including both ends. A zero-length line
+// yields just the one tile.
+#[allow(dead_code)]
+pub fn line(from: (i32, i32), to: (i32, i32)) -> Line {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    Line {
+        next: Some(from),
+        to,
+        step: ((to.0 - from.0).signum(), (to.1 - from.1).signum()),
+        delta: (dx, dy),
+        error: dx + dy,
+    }
+}
+
+pub struct Line {
+    next: Option<(i32, i32)>,
+    to: (i32, i32),
+    step: (i32, i32),
+    // (|dx|, -|dy|), as in the all-octant form of Bresenham's algorithm
+    delta: (i32, i32),
+    error: i32,
+}
+
+impl Iterator for Line {
+    type Item = (i32, i32);
+
+    fn next(&mut self) -> Option<(i32, i32)> {
+        let (mut x, mut y) = self.next?;
+        let current = (x, y);
+        if current == self.to {
+            self.next = None;
+            return Some(current);
+        }
+        let doubled = 2 * self.error;
+        if doubled >= self.delta.1 {
+            self.error += self.delta.1;
+            x += self.step.0;
+        }
+        if doubled <= self.delta.0 {
+            self.error += self.delta.0;
+            y += self.step.1;
+        }
+        self.next = Some((x, y));
+        Some(current)
+    }
+}
+
+// The outline of a circle, by the midpoint circle algorithm. Each point is listed once; a
+// radius of 0 is just the center.
+#[allow(dead_code)]
+pub fn circle_points(center: (i32, i32), radius: i32) -> Vec<(i32, i32)> {
+    let mut points = vec![];
+    let (mut x, mut y) = (radius, 0);
+    let mut error = 1 - radius;
+    while x >= y {
+        for &(px, py) in &[
+            (x, y),
+            (y, x),
+            (-y, x),
+            (-x, y),
+            (-x, -y),
+            (-y, -x),
+            (y, -x),
+            (x, -y),
+        ] {
+            let point = (center.0 + px, center.1 + py);
+            if !points.contains(&point) {
+                points.push(point);
+            }
+        }
+        y += 1;
+        if error < 0 {
+            error += 2 * y + 1;
+        } else {
+            x -= 1;
+            error += 2 * (y - x) + 1;
+        }
+    }
+    points
+}
+
+// Every tile whose center is within `radius` of the center tile, row by row.
+#[allow(dead_code)]
+pub fn tiles_in_radius(center: (i32, i32), radius: i32) -> Vec<(i32, i32)> {
+    let mut tiles = vec![];
+    for y in center.1 - radius..=center.1 + radius {
+        for x in center.0 - radius..=center.0 + radius {
+            if distance(center, (x, y)) <= radius as f32 {
+                tiles.push((x, y));
+            }
+        }
+    }
+    tiles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every line starts and ends at its endpoints and moves one tile at a time.
+    fn check_line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+        let points: Vec<_> = line(from, to).collect();
+        assert_eq!(points.first(), Some(&from));
+        assert_eq!(points.last(), Some(&to));
+        assert_eq!(points.len() as i32, chebyshev_distance(from, to) + 1);
+        for pair in points.windows(2) {
+            assert_eq!(chebyshev_distance(pair[0], pair[1]), 1);
+        }
+        points
+    }
+
+    #[test]
+    fn shallow_line() {
+        assert_eq!(
+            check_line((0, 0), (5, 2)),
+            vec![(0, 0), (1, 0), (2, 1), (3, 1), (4, 2), (5, 2)]
+        );
+    }
+
+    #[test]
+    fn steep_line() {
+        assert_eq!(
+            check_line((0, 0), (2, 5)),
+            vec![(0, 0), (0, 1), (1, 2), (1, 3), (2, 4), (2, 5)]
+        );
+    }
+
+    #[test]
+    fn lines_in_negative_directions() {
+        assert_eq!(
+            check_line((3, 3), (-2, 1)),
+            vec![(3, 3), (2, 3), (1, 2), (0, 2), (-1, 1), (-2, 1)]
+        );
+        check_line((0, 0), (-4, -7));
+        check_line((-1, 5), (6, -3));
+    }
+
+    #[test]
+    fn straight_and_diagonal_lines() {
+        assert_eq!(
+            check_line((2, 1), (2, 4)),
+            vec![(2, 1), (2, 2), (2, 3), (2, 4)]
+        );
+        assert_eq!(
+            check_line((4, 0), (1, 0)),
+            vec![(4, 0), (3, 0), (2, 0), (1, 0)]
+        );
+        assert_eq!(
+            check_line((0, 0), (-3, 3)),
+            vec![(0, 0), (-1, 1), (-2, 2), (-3, 3)]
+        );
+    }
+
+    #[test]
+    fn zero_length_line() {
+        assert_eq!(line((7, -2), (7, -2)).collect::<Vec<_>>(), vec![(7, -2)]);
+    }
+
+    #[test]
+    fn chebyshev_distance_counts_diagonal_steps_once() {
+        assert_eq!(chebyshev_distance((0, 0), (0, 0)), 0);
+        assert_eq!(chebyshev_distance((0, 0), (3, 3)), 3);
+        assert_eq!(chebyshev_distance((1, 2), (6, 4)), 5);
+        assert_eq!(chebyshev_distance((1, 2), (-1, -6)), 8);
+        assert_eq!(
+            chebyshev_distance((5, 5), (2, 9)),
+            chebyshev_distance((2, 9), (5, 5))
+        );
+    }
+
+    #[test]
+    fn circle_points_are_listed_once_at_the_radius() {
+        assert_eq!(circle_points((4, 4), 0), vec![(4, 4)]);
+        for radius in 1..8 {
+            let center = (10, -3);
+            let points = circle_points(center, radius);
+            for (i, point) in points.iter().enumerate() {
+                assert!(!points[..i].contains(point), "{:?} listed twice", point);
+                assert!((distance(center, *point) - radius as f32).abs() < 1.0);
+            }
+            for &(dx, dy) in &[(radius, 0), (-radius, 0), (0, radius), (0, -radius)] {
+                assert!(points.contains(&(center.0 + dx, center.1 + dy)));
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_in_radius_fill_the_circle() {
+        assert_eq!(tiles_in_radius((1, 1), 0), vec![(1, 1)]);
+        assert_eq!(
+            tiles_in_radius((0, 0), 1),
+            vec![(0, -1), (-1, 0), (0, 0), (1, 0), (0, 1)]
+        );
+        let tiles = tiles_in_radius((3, 3), 2);
+        assert_eq!(tiles.len(), 13);
+        assert!(tiles.iter().all(|&tile| distance((3, 3), tile) <= 2.0));
+        assert!(!tiles.contains(&(5, 5)));
+    }
+}
@@ -0,0 +1,173 @@
+// A small file logger with per-target levels, so map generation and rendering can be traced
+// without printing over the terminal the game may be drawn into.
+//
+// Levels are set with a filter like "info,mapgen=trace,fov=off": a default level followed by
+// overrides for individual targets. Log with the macros, e.g. `debug!("mapgen", "room {:?}", r)`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+// How many of the latest warnings and errors are kept for the debug overlay
+const RECENT_WARNINGS: usize = 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("Unknown log level: {}", name)),
+        }
+    }
+}
+
+// The most verbose level enabled by default and for each overridden target. None means off.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    default: Option<Level>,
+    targets: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        let max = self
+            .targets
+            .iter()
+            .find(|(name, _)| name == target)
+            .map_or(self.default, |&(_, max)| max);
+        max.is_some_and(|max| level <= max)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let parse_level = |name: &str| match name {
+            "off" => Ok(None),
+            _ => name.parse().map(Some),
+        };
+        let mut filter = Filter {
+            default: Some(Level::Warn),
+            targets: vec![],
+        };
+        for part in spec.split(',').filter(|part| !part.is_empty()) {
+            match part.find('=') {
+                Some(split) => {
+                    let level = parse_level(&part[split + 1..])?;
+                    filter.targets.push((part[..split].to_string(), level));
+                }
+                None => filter.default = parse_level(part)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    file: Mutex<File>,
+    start: Instant,
+    recent_warnings: Mutex<VecDeque<String>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// Start logging to the file at `path`, replacing its contents. Before this is called, or if it
+// fails, logging does nothing.
+pub fn init(path: &Path, filter: Filter) -> io::Result<()> {
+    let file = File::create(path)?;
+    let logger = Logger {
+        filter,
+        file: Mutex::new(file),
+        start: Instant::now(),
+        recent_warnings: Mutex::new(VecDeque::new()),
+    };
+    LOGGER
+        .set(logger)
+        .map_err(|_| io::Error::other("logging is already initialized"))
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    LOGGER
+        .get()
+        .is_some_and(|logger| logger.filter.enabled(level, target))
+}
+
+// Write one line; the macros check `enabled` first so disabled messages are never formatted.
+pub fn write(level: Level, target: &str, args: fmt::Arguments) {
+    if let Some(logger) = LOGGER.get() {
+        let elapsed = logger.start.elapsed();
+        if let Ok(mut file) = logger.file.lock() {
+            // A failed write can't be reported anywhere useful, so it is dropped.
+            let _ = writeln!(
+                file,
+                "{:>4}.{:03} {:<5} {}: {}",
+                elapsed.as_secs(),
+                elapsed.subsec_millis(),
+                format!("{:?}", level).to_uppercase(),
+                target,
+                args
+            );
+        }
+        if level <= Level::Warn {
+            if let Ok(mut recent) = logger.recent_warnings.lock() {
+                if recent.len() == RECENT_WARNINGS {
+                    recent.pop_front();
+                }
+                recent.push_back(format!("{}: {}", target, args));
+            }
+        }
+    }
+}
+
+// The latest enabled warnings and errors, oldest first.
+pub fn recent_warnings() -> Vec<String> {
+    LOGGER
+        .get()
+        .and_then(|logger| logger.recent_warnings.lock().ok())
+        .map_or_else(Vec::new, |recent| recent.iter().cloned().collect())
+}
+
+macro_rules! log {
+    ($level:expr, $target:expr, $($arg:tt)+) => {
+        if $crate::logging::enabled($level, $target) {
+            $crate::logging::write($level, $target, format_args!($($arg)+));
+        }
+    };
+}
+
+macro_rules! warn {
+    ($target:expr, $($arg:tt)+) => { log!($crate::logging::Level::Warn, $target, $($arg)+) };
+}
+
+macro_rules! info {
+    ($target:expr, $($arg:tt)+) => { log!($crate::logging::Level::Info, $target, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($target:expr, $($arg:tt)+) => { log!($crate::logging::Level::Debug, $target, $($arg)+) };
+}
+
+macro_rules! trace {
+    ($target:expr, $($arg:tt)+) => { log!($crate::logging::Level::Trace, $target, $($arg)+) };
+}
@@ -1,52 +1,670 @@
-use rand::Rng;
+#[macro_use]
+mod logging;
+mod alloc_count;
+mod geometry;
+mod trace;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::io;
+use std::ops::{Index, IndexMut};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tcod::colors::{self, Color};
 use tcod::console::*;
+use tcod::input::{self, Event, Key, KeyCode, Mouse};
 use tcod::map::{FovAlgorithm, Map as FovMap};
+use tcod::pathfinding::AStar;
+use trace::{Entry, Recorder, Trace};
+
+#[cfg(debug_assertions)]
+#[global_allocator]
+static ALLOCATOR: alloc_count::CountingAllocator = alloc_count::CountingAllocator;
 
-// Window size
+// Default window size, and the smallest one the layout still fits in
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
+const MIN_SCREEN_WIDTH: i32 = 60;
+const MIN_SCREEN_HEIGHT: i32 = 35;
+
+// Rows below the map reserved for the panel. Its first row describes the player's tile, the
+// rest holds the HUD on the left and the message log beside it.
+const PANEL_HEIGHT: i32 = 5;
+const HUD_WIDTH: i32 = 20;
+const MSG_X: i32 = HUD_WIDTH + 2;
+
+// Where F12 saves screenshots unless --screenshot-dir says otherwise
+const SCREENSHOT_DIR: &str = "screenshots";
 
-// Frame rate
+// Where F10 saves bug reports, and how many turns apart --record writes state checksums
+const BUG_REPORT_DIR: &str = "bug-reports";
+const TRACE_CHECK_INTERVAL: u32 = 10;
+
+// Log file and levels unless --log-file and --log say otherwise
+const LOG_FILE: &str = "rusty-roguelike.log";
+const LOG_FILTER: &str = "info";
+// Default relative weights of the corridor styles
+const CORRIDOR_MIX: &str = "narrow=6,wide=2,stepped=2";
+
+// Glyph colors closer than this (as a distance in RGB space) to both ground colors get a
+// warning at startup, unless --min-contrast says otherwise
+const MIN_CONTRAST: f32 = 60.0;
+
+// Frame rate, and how many frames the debug overlay averages over
 const LIMIT_FPS: i32 = 20;
+const FRAME_SAMPLES: usize = 20;
+
+// Keypresses buffered beyond this are dropped, so a burst of input can't queue up a long walk
+const INPUT_QUEUE_LIMIT: usize = 5;
 
-// Map size and colors
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 45;
-const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
-const COLOR_LIGHT_WALL: Color = Color {
-    r: 130,
-    g: 110,
-    b: 50,
-};
-const COLOR_DARK_GROUND: Color = Color {
-    r: 50,
-    g: 50,
-    b: 150,
-};
-const COLOR_LIGHT_GROUND: Color = Color {
-    r: 200,
-    g: 180,
-    b: 50,
-};
-
-// Room size and max rooms
+// Color schemes selectable with --palette, the first one is the default
+const PALETTES: [(&str, Palette); 3] = [
+    (
+        "classic",
+        Palette {
+            dark_wall: Color::new(0, 0, 100),
+            light_wall: Color::new(130, 110, 50),
+            dark_ground: Color::new(50, 50, 150),
+            light_ground: Color::new(200, 180, 50),
+            dark_water: Color::new(20, 40, 110),
+            light_water: Color::new(40, 90, 200),
+            dark_bridge: Color::new(70, 50, 30),
+            light_bridge: Color::new(150, 100, 50),
+            overview_wall: Color::new(150, 150, 150),
+            overview_ground: Color::new(40, 40, 40),
+            overview_water: Color::new(40, 80, 200),
+            overview_player: Color::new(255, 0, 0),
+            player: colors::WHITE,
+            npc: colors::YELLOW,
+            message: colors::WHITE,
+            path_preview: Color::new(60, 140, 60),
+            background: colors::BLACK,
+            overlay_text: colors::LIGHT_GREY,
+        },
+    ),
+    (
+        "high-contrast",
+        Palette {
+            dark_wall: Color::new(70, 70, 70),
+            light_wall: Color::new(240, 240, 240),
+            dark_ground: Color::new(20, 20, 20),
+            light_ground: Color::new(110, 110, 110),
+            dark_water: Color::new(0, 0, 90),
+            light_water: Color::new(0, 80, 255),
+            dark_bridge: Color::new(60, 40, 0),
+            light_bridge: Color::new(170, 110, 0),
+            overview_wall: Color::new(255, 255, 255),
+            overview_ground: Color::new(60, 60, 60),
+            overview_water: Color::new(0, 100, 255),
+            overview_player: Color::new(255, 255, 0),
+            player: Color::new(255, 255, 0),
+            npc: Color::new(0, 255, 255),
+            message: colors::WHITE,
+            path_preview: Color::new(200, 0, 200),
+            background: colors::BLACK,
+            overlay_text: colors::WHITE,
+        },
+    ),
+    (
+        "deuteranopia",
+        Palette {
+            dark_wall: Color::new(0, 40, 110),
+            light_wall: Color::new(230, 160, 0),
+            dark_ground: Color::new(60, 90, 150),
+            light_ground: Color::new(250, 220, 130),
+            dark_water: Color::new(20, 50, 100),
+            light_water: Color::new(40, 100, 200),
+            dark_bridge: Color::new(100, 60, 85),
+            light_bridge: Color::new(204, 121, 167),
+            overview_wall: Color::new(200, 200, 200),
+            overview_ground: Color::new(50, 50, 50),
+            overview_water: Color::new(0, 114, 178),
+            overview_player: Color::new(0, 114, 178),
+            player: colors::WHITE,
+            npc: Color::new(0, 114, 178),
+            message: colors::WHITE,
+            path_preview: Color::new(86, 180, 233),
+            background: colors::BLACK,
+            overlay_text: colors::LIGHT_GREY,
+        },
+    ),
+];
+
+// Width of the minimap in the right corner of the panel; it is as tall as the panel
+const MINIMAP_SIZE: i32 = 15;
+
+// Room size and max rooms, unless --room-min-size, --room-max-size and --max-rooms say otherwise
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
 const MAX_ROOMS: i32 = 30;
+// Chance that a room overlapping others is merged into them instead of rejected, and the most
+// rooms one merged compound may be made of
+const ROOM_MERGE_CHANCE: f32 = 0.2;
+const MAX_MERGED_ROOMS: usize = 3;
+// Caves: how much of the map the drunkard's walk opens up, and how often it changes direction
+const CAVE_FLOOR_FRACTION: f32 = 0.4;
+const CAVE_TURN_CHANCE: f32 = 0.25;
+
+// Mazes: chance that a dead end has a wall knocked out to make a loop
+const MAZE_LOOP_CHANCE: f32 = 0.1;
+
+// Dungeon level a new game starts on
+const START_DEPTH: u32 = 1;
+
+// How many times generation is retried when it produces an unplayable map
+const MAP_GEN_ATTEMPTS: u32 = 10;
+
+// Extra tunnels between rooms that aren't already joined directly, so levels have loops, unless
+// --loop-corridors says otherwise
+const LOOP_CORRIDORS: usize = 3;
+
+// Interior pillars: one per this many floor tiles, in rooms at least this wide and tall inside
+const ROOM_AREA_PER_PILLAR: i32 = 12;
+const PILLAR_MIN_ROOM_SIZE: i32 = 5;
+
+// Chance that a level gets a river, its width, and how often it changes course
+const RIVER_CHANCE: f32 = 0.3;
+const RIVER_WIDTH: i32 = 2;
+const RIVER_TURN_CHANCE: f32 = 0.3;
 
 // Lighting and field of view
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
 const TORCH_RADIUS: i32 = 10;
 
+// How many turns a creature's ghost lingers where it was last seen, and how far its glyph is
+// faded into the background
+const GHOST_MEMORY_TURNS: u32 = 100;
+const GHOST_FADE: f32 = 0.6;
+
+// Every color the game draws with. Map colors come in a dark (remembered) and a light (visible)
+// variant, and the overview colors are brighter so the layout reads at a glance.
+#[derive(Clone, Copy, Debug)]
+struct Palette {
+    dark_wall: Color,
+    light_wall: Color,
+    dark_ground: Color,
+    light_ground: Color,
+    dark_water: Color,
+    light_water: Color,
+    dark_bridge: Color,
+    light_bridge: Color,
+    overview_wall: Color,
+    overview_ground: Color,
+    overview_water: Color,
+    overview_player: Color,
+    player: Color,
+    npc: Color,
+    message: Color,
+    path_preview: Color,
+    // Behind unexplored tiles and anything else that isn't drawn.
+    background: Color,
+    // Text of the debug overlay, which is drawn on the background color.
+    overlay_text: Color,
+}
+
+impl Palette {
+    // Entities whose glyph color can be overridden with --color, and the color each one uses.
+    pub fn entity_colors(&self) -> [(&'static str, Color); 2] {
+        [("player", self.player), ("stranger", self.npc)]
+    }
+
+    pub fn set_entity_color(&mut self, name: &str, color: Color) -> Result<(), String> {
+        match name {
+            "player" => self.player = color,
+            "stranger" => self.npc = color,
+            _ => {
+                let names: Vec<_> = self.entity_colors().iter().map(|(name, _)| *name).collect();
+                return Err(format!(
+                    "Unknown entity {}, expected one of: {}",
+                    name,
+                    names.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Entities whose glyphs would be hard to make out on both lit and unlit ground.
+    pub fn low_contrast_entities(&self, min_contrast: f32) -> Vec<&'static str> {
+        self.entity_colors()
+            .iter()
+            .filter(|(_, color)| {
+                color_distance(*color, self.light_ground) < min_contrast
+                    && color_distance(*color, self.dark_ground) < min_contrast
+            })
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
+    pub fn by_name(name: &str) -> Result<Self, String> {
+        PALETTES
+            .iter()
+            .find(|(palette_name, _)| *palette_name == name)
+            .map(|(_, palette)| *palette)
+            .ok_or_else(|| {
+                let names: Vec<_> = PALETTES.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "Unknown palette {}, expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+// The libtcod consoles, the FOV map and this frame's input, passed around together.
+struct Tcod {
+    root: Root,
+    con: Offscreen,
+    panel: Offscreen,
+    fov: FovMap,
+    // Each tile's background (out of view, in view), so the palette isn't matched against every
+    // tile each frame. Built from the map like the FOV map, and rebuilt along with it.
+    tile_colors: Vec<Vec<(Color, Color)>>,
+    fov_recomputes: u32,
+    // Whether the frame being drawn recomputed the FOV.
+    fov_recomputed: bool,
+    key: Key,
+    // Keypresses waiting their turn, oldest first; one is handled per frame.
+    key_queue: VecDeque<Key>,
+    mouse: Mouse,
+    // Reused for the panel's text, so drawing it doesn't allocate every frame.
+    hud_text: String,
+}
+
+// Display state that isn't part of the game: toggles, pending screenshots and the mouse path
+// preview.
+struct View {
+    show_overview: bool,
+    show_debug_overlay: bool,
+    screenshot_requested: bool,
+    bug_report_requested: bool,
+    // Player position and hovered tile the preview was computed for.
+    preview_key: Option<((i32, i32), (i32, i32))>,
+    path_preview: Vec<(i32, i32)>,
+}
+
+impl View {
+    // Whether to draw the debug overlay this frame. Screenshots, including the one in a bug
+    // report, leave it out unless asked to keep it.
+    fn shows_debug_overlay(&self, config: &Config) -> bool {
+        let capturing = self.screenshot_requested || self.bug_report_requested;
+        self.show_debug_overlay && (!capturing || config.screenshot_overlay)
+    }
+}
+
+// Settings that can be changed from the command line.
+#[derive(Clone, Debug)]
+struct Config {
+    screen_width: i32,
+    screen_height: i32,
+    palette: Palette,
+    screenshot_dir: PathBuf,
+    log_file: PathBuf,
+    log_filter: logging::Filter,
+    generator: Generator,
+    room_min_size: i32,
+    room_max_size: i32,
+    max_rooms: i32,
+    loop_corridors: usize,
+    corridors: CorridorMix,
+    // Seed for map generation; a random one is picked when this is None.
+    seed: Option<u64>,
+    // Print the generated map as text and exit instead of starting the game.
+    dump_map: bool,
+    // Play on a map loaded from a text dump instead of a generated one.
+    load_map: Option<PathBuf>,
+    // Write a trace of the game for bug reports.
+    record: Option<PathBuf>,
+    // Replay a trace without opening a window and report where it stops matching.
+    verify: Option<PathBuf>,
+    // Glyphs closer than this to the ground colors are reported as hard to see.
+    min_contrast: f32,
+    // Enables the F3 debug overlay.
+    debug: bool,
+    // Keeps the debug overlay in screenshots.
+    screenshot_overlay: bool,
+}
+
+impl Config {
+    // Read settings from arguments like `--width 100 --palette high-contrast --log mapgen=trace`.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = Config {
+            screen_width: SCREEN_WIDTH,
+            screen_height: SCREEN_HEIGHT,
+            palette: PALETTES[0].1,
+            screenshot_dir: PathBuf::from(SCREENSHOT_DIR),
+            log_file: PathBuf::from(LOG_FILE),
+            log_filter: LOG_FILTER.parse()?,
+            generator: Generator::Rooms,
+            room_min_size: ROOM_MIN_SIZE,
+            room_max_size: ROOM_MAX_SIZE,
+            max_rooms: MAX_ROOMS,
+            loop_corridors: LOOP_CORRIDORS,
+            corridors: CORRIDOR_MIX.parse()?,
+            seed: None,
+            dump_map: false,
+            load_map: None,
+            record: None,
+            verify: None,
+            debug: false,
+            screenshot_overlay: false,
+            min_contrast: MIN_CONTRAST,
+        };
+        // Applied after all arguments are read, so they survive a later --palette.
+        let mut color_overrides = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--width" => config.screen_width = parse_value(&arg, args.next())?,
+                "--height" => config.screen_height = parse_value(&arg, args.next())?,
+                "--palette" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    config.palette = Palette::by_name(&name)?;
+                }
+                "--screenshot-dir" => config.screenshot_dir = parse_value(&arg, args.next())?,
+                "--log-file" => config.log_file = parse_value(&arg, args.next())?,
+                "--log" => {
+                    let spec: String = parse_value(&arg, args.next())?;
+                    config.log_filter = spec.parse()?;
+                }
+                "--generator" => {
+                    let name: String = parse_value(&arg, args.next())?;
+                    config.generator = name.parse()?;
+                }
+                "--room-min-size" => config.room_min_size = parse_value(&arg, args.next())?,
+                "--room-max-size" => config.room_max_size = parse_value(&arg, args.next())?,
+                "--max-rooms" => config.max_rooms = parse_value(&arg, args.next())?,
+                "--loop-corridors" => config.loop_corridors = parse_value(&arg, args.next())?,
+                "--corridors" => {
+                    let spec: String = parse_value(&arg, args.next())?;
+                    config.corridors = spec.parse()?;
+                }
+                "--seed" => config.seed = Some(parse_value(&arg, args.next())?),
+                "--dump-map" => {
+                    config.seed = Some(parse_value(&arg, args.next())?);
+                    config.dump_map = true;
+                }
+                "--load-map" => config.load_map = Some(parse_value(&arg, args.next())?),
+                "--record" => config.record = Some(parse_value(&arg, args.next())?),
+                "--verify" => config.verify = Some(parse_value(&arg, args.next())?),
+                "--debug" => config.debug = true,
+                "--screenshot-overlay" => config.screenshot_overlay = true,
+                "--color" => {
+                    let spec: String = parse_value(&arg, args.next())?;
+                    let split = spec
+                        .find('=')
+                        .ok_or_else(|| format!("Invalid color override: {}", spec))?;
+                    let color = parse_color(&spec[split + 1..])?;
+                    color_overrides.push((spec[..split].to_string(), color));
+                }
+                "--min-contrast" => config.min_contrast = parse_value(&arg, args.next())?,
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        for (name, color) in color_overrides {
+            config.palette.set_entity_color(&name, color)?;
+        }
+        Ok(config)
+    }
+
+    // A hash of the settings that shape the map, so a trace is only replayed with the settings
+    // it was recorded with. The seed is stored in the trace separately.
+    pub fn trace_hash(&self, layout: &Layout) -> u64 {
+        let settings = format!(
+            "{:?} {:?} {} {} {} {} {:?} {:?}",
+            layout,
+            self.generator,
+            self.room_min_size,
+            self.room_max_size,
+            self.max_rooms,
+            self.loop_corridors,
+            self.corridors,
+            self.load_map
+        );
+        trace::fnv1a(settings.as_bytes())
+    }
+
+    // Check the settings that only make sense together, once the map size is known. Nothing is
+    // clamped: a bad combination is rejected with the reason.
+    pub fn validate(&self, layout: &Layout) -> Result<(), String> {
+        // a room's size includes its walls, so 3 is the smallest with floor inside
+        if self.room_min_size < 3 {
+            return Err(format!(
+                "--room-min-size is {}, it must be at least 3",
+                self.room_min_size
+            ));
+        }
+        if self.room_min_size > self.room_max_size {
+            return Err(format!(
+                "--room-min-size ({}) is larger than --room-max-size ({})",
+                self.room_min_size, self.room_max_size
+            ));
+        }
+        if self.room_max_size >= cmp::min(layout.map_width, layout.map_height) {
+            return Err(format!(
+                "--room-max-size ({}) doesn't fit in the {}x{} map",
+                self.room_max_size, layout.map_width, layout.map_height
+            ));
+        }
+        if self.max_rooms < 1 {
+            return Err(format!(
+                "--max-rooms is {}, it must be at least 1",
+                self.max_rooms
+            ));
+        }
+        Ok(())
+    }
+}
+
+// A color written as "#RRGGBB".
+fn parse_color(text: &str) -> Result<Color, String> {
+    let invalid = || format!("Invalid color {}, expected #RRGGBB", text);
+    let hex = text.strip_prefix('#').ok_or_else(invalid)?;
+    // from_str_radix alone would also take a sign, as in "#+1+2+3"
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(Color::new(channel(0)?, channel(2)?, channel(4)?))
+}
+
+// Straight-line distance between two colors in RGB space.
+fn color_distance(a: Color, b: Color) -> f32 {
+    let channel = |a: u8, b: u8| (a as f32 - b as f32).powi(2);
+    (channel(a.r, b.r) + channel(a.g, b.g) + channel(a.b, b.b)).sqrt()
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// Which map generator builds the level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Generator {
+    // Rectangular rooms joined by tunnels
+    Rooms,
+    // Winding caverns dug by a drunkard's walk
+    Caves,
+    // A maze of one-tile corridors with a few loops
+    Maze,
+}
+
+impl Generator {
+    pub fn name(self) -> &'static str {
+        match self {
+            Generator::Rooms => "rooms",
+            Generator::Caves => "caves",
+            Generator::Maze => "maze",
+        }
+    }
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "rooms" => Ok(Generator::Rooms),
+            "caves" => Ok(Generator::Caves),
+            "maze" => Ok(Generator::Maze),
+            _ => Err(format!(
+                "Unknown generator {}, expected one of: rooms, caves, maze",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CorridorStyle {
+    // One tile wide, one straight leg each way
+    Narrow,
+    // Two tiles wide where the map allows
+    Wide,
+    // A staircase of short steps heading roughly straight for the target
+    Stepped,
+}
+
+// How often each corridor style is picked, as relative weights.
+#[derive(Clone, Copy, Debug)]
+struct CorridorMix {
+    narrow: u32,
+    wide: u32,
+    stepped: u32,
+}
+
+impl CorridorMix {
+    pub fn pick(&self, rng: &mut StdRng) -> CorridorStyle {
+        let total = self.narrow + self.wide + self.stepped;
+        let roll = rng.gen_range(0, total);
+        if roll < self.narrow {
+            CorridorStyle::Narrow
+        } else if roll < self.narrow + self.wide {
+            CorridorStyle::Wide
+        } else {
+            CorridorStyle::Stepped
+        }
+    }
+}
+
+impl FromStr for CorridorMix {
+    type Err = String;
+
+    // Weights like "narrow=6,wide=2,stepped=2"; styles that aren't listed are never used.
+    fn from_str(spec: &str) -> Result<Self, String> {
+        let mut mix = CorridorMix {
+            narrow: 0,
+            wide: 0,
+            stepped: 0,
+        };
+        for part in spec.split(',').filter(|part| !part.is_empty()) {
+            let invalid = || format!("Invalid corridor weight: {}", part);
+            let split = part.find('=').ok_or_else(invalid)?;
+            let weight = part[split + 1..].parse().map_err(|_| invalid())?;
+            match &part[..split] {
+                "narrow" => mix.narrow = weight,
+                "wide" => mix.wide = weight,
+                "stepped" => mix.stepped = weight,
+                name => return Err(format!("Unknown corridor style: {}", name)),
+            }
+        }
+        if mix.narrow + mix.wide + mix.stepped == 0 {
+            return Err("At least one corridor style needs a weight above 0".to_string());
+        }
+        Ok(mix)
+    }
+}
+
+// Sizes of the screen regions, derived from the actual console size.
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+    screen_width: i32,
+    screen_height: i32,
+    map_width: i32,
+    map_height: i32,
+    // Left edge of the minimap in the panel.
+    minimap_x: i32,
+    panel_y: i32,
+}
+
+impl Layout {
+    pub fn new(screen_width: i32, screen_height: i32) -> Result<Self, String> {
+        if screen_width < MIN_SCREEN_WIDTH || screen_height < MIN_SCREEN_HEIGHT {
+            return Err(format!(
+                "Console size {}x{} is too small, it must be at least {}x{}",
+                screen_width, screen_height, MIN_SCREEN_WIDTH, MIN_SCREEN_HEIGHT
+            ));
+        }
+        Ok(Layout {
+            screen_width,
+            screen_height,
+            map_width: screen_width,
+            map_height: screen_height - PANEL_HEIGHT,
+            minimap_x: screen_width - MINIMAP_SIZE,
+            panel_y: screen_height - PANEL_HEIGHT,
+        })
+    }
+}
+
+// What a level contains, for the arrival message and the debug overlay.
+#[derive(Clone, Copy, Debug, Default)]
+struct LevelMetadata {
+    floor_tiles: usize,
+    water_tiles: usize,
+    bridge_tiles: usize,
+}
+
+impl LevelMetadata {
+    // Count the features of a finished map, whether it was generated or loaded from a dump.
+    pub fn survey(map: &Map) -> Self {
+        let mut level = LevelMetadata::default();
+        for tile in map.iter().flatten() {
+            match tile.terrain {
+                Terrain::Wall => {}
+                Terrain::Floor => level.floor_tiles += 1,
+                Terrain::Water => level.water_tiles += 1,
+                Terrain::Bridge => level.bridge_tiles += 1,
+            }
+        }
+        level
+    }
+
+    // Hints about the level, shown once when the player arrives.
+    pub fn feelings(&self) -> Vec<&'static str> {
+        let mut feelings = vec![];
+        if self.water_tiles > 0 {
+            feelings.push("You hear running water.");
+        }
+        feelings
+    }
+}
+
+// What a tile looks like. How it behaves is up to the flags on Tile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Terrain {
+    Wall,
+    Floor,
+    Water,
+    Bridge,
+}
+
 // A tile of the map, and its properties.
 #[derive(Clone, Copy, Debug)]
 struct Tile {
     blocked: bool,
     block_sight: bool,
     explored: bool,
+    terrain: Terrain,
 }
 
 impl Tile {
@@ -55,6 +673,7 @@ impl Tile {
             blocked: false,
             block_sight: false,
             explored: false,
+            terrain: Terrain::Floor,
         }
     }
 
@@ -63,64 +682,461 @@ impl Tile {
             blocked: true,
             block_sight: true,
             explored: false,
+            terrain: Terrain::Wall,
+        }
+    }
+
+    // Deep water: seen across, never walked into.
+    pub fn water() -> Self {
+        Tile {
+            blocked: true,
+            block_sight: false,
+            explored: false,
+            terrain: Terrain::Water,
+        }
+    }
+
+    pub fn bridge() -> Self {
+        Tile {
+            blocked: false,
+            block_sight: false,
+            explored: false,
+            terrain: Terrain::Bridge,
         }
     }
 }
 
 type Map = Vec<Vec<Tile>>;
 
-fn make_map() -> (Map, (i32, i32)) {
+// Bounds-checked tile access. Indexing a Map directly panics on coordinates off the map, which
+// is easy to hit with offsets near the edges, so only loops over the map's own size do that.
+trait TileMap {
+    fn tile(&self, x: i32, y: i32) -> Option<&Tile>;
+
+    // Replace a tile. Writing off the map is a bug in the caller: it fails a debug assertion
+    // and is otherwise ignored.
+    fn set_tile(&mut self, x: i32, y: i32, tile: Tile);
+
+    // Whether (x, y) can't be walked on. Anything off the map counts as blocked.
+    fn is_blocked(&self, x: i32, y: i32) -> bool {
+        self.tile(x, y).is_none_or(|tile| tile.blocked)
+    }
+}
+
+impl TileMap for Map {
+    fn tile(&self, x: i32, y: i32) -> Option<&Tile> {
+        if x < 0 || y < 0 {
+            return None;
+        }
+        self.get(x as usize)?.get(y as usize)
+    }
+
+    fn set_tile(&mut self, x: i32, y: i32, tile: Tile) {
+        let on_map = x >= 0 && y >= 0 && (x as usize) < self.len() && (y as usize) < self[0].len();
+        debug_assert!(on_map, "tile ({}, {}) is off the map", x, y);
+        if on_map {
+            self[x as usize][y as usize] = tile;
+        }
+    }
+}
+
+// A creature's glyph where the player last saw it, kept while it is out of sight.
+#[derive(Clone, Copy, Debug)]
+struct Ghost {
+    x: i32,
+    y: i32,
+    char: char,
+    color: Color,
+    turn: u32,
+}
+
+// Log of messages shown in the panel, oldest first.
+struct Messages {
+    messages: Vec<(String, Color)>,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self { messages: vec![] }
+    }
+
+    pub fn add<T: Into<String>>(&mut self, message: T, color: Color) {
+        self.messages.push((message.into(), color));
+    }
+
+    // The newest messages first.
+    pub fn iter_newest(&self) -> impl Iterator<Item = &(String, Color)> {
+        self.messages.iter().rev()
+    }
+}
+
+// The current level and what the player remembers about it.
+struct Game {
+    map: Map,
+    level: LevelMetadata,
+    // Seed the map was generated from, for map dumps.
+    seed: u64,
+    // How far down the player is; the first level is START_DEPTH.
+    depth: u32,
+    player: ObjectId,
+    // Keyed by the object seen; the player never gets one.
+    ghosts: HashMap<ObjectId, Ghost>,
+    turn: u32,
+    messages: Messages,
+    // Remaining steps of a click-to-move walk.
+    travel: VecDeque<(i32, i32)>,
+    // Set by anything that changes what the player can see: moving, or (once they exist)
+    // opening doors, changing the sight radius or adding lights. The next render recomputes
+    // the FOV and clears it.
+    fov_dirty: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    TookTurn,
+    DidntTakeTurn,
+    Exit,
+}
+
+// Why generate_map gave up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MapGenError {
+    // Every attempt left the player's start in solid rock.
+    NoPlayableMap { generator: Generator, attempts: u32 },
+}
+
+impl fmt::Display for MapGenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapGenError::NoPlayableMap {
+                generator,
+                attempts,
+            } => write!(
+                f,
+                "Could not generate a playable {} map in {} attempts",
+                generator.name(),
+                attempts
+            ),
+        }
+    }
+}
+
+// Build a level with the configured generator. A map the player can't start on, e.g. because
+// no room could be placed and the start was left in solid rock, is thrown away and generated
+// again, and after MAP_GEN_ATTEMPTS failures the error is returned instead.
+fn generate_map(
+    layout: &Layout,
+    config: &Config,
+    rng: &mut StdRng,
+) -> Result<(Map, (i32, i32)), MapGenError> {
+    for attempt in 1..=MAP_GEN_ATTEMPTS {
+        let (map, start) = match config.generator {
+            Generator::Rooms => make_map(layout, config, rng),
+            Generator::Caves => make_caves(layout, rng),
+            Generator::Maze => make_maze(layout, rng),
+        };
+        if !map.is_blocked(start.0, start.1) {
+            return Ok((map, start));
+        }
+        warn!(
+            "mapgen",
+            "Attempt {} left the start {:?} in rock, generating again", attempt, start
+        );
+    }
+    Err(MapGenError::NoPlayableMap {
+        generator: config.generator,
+        attempts: MAP_GEN_ATTEMPTS,
+    })
+}
+
+fn make_map(layout: &Layout, config: &Config, rng: &mut StdRng) -> (Map, (i32, i32)) {
+    let (mut map, starting_position, rooms) = make_rooms(layout, config, rng);
+
+    if rng.gen::<f32>() < RIVER_CHANCE {
+        add_river(&mut map, starting_position, rng);
+    }
+
+    info!(
+        "mapgen",
+        "Generated {} rooms, the player starts at {:?}",
+        rooms.len(),
+        starting_position
+    );
+    (map, starting_position)
+}
+
+// Place the rooms and dig the tunnels between them, returning the rooms along with the map.
+fn make_rooms(layout: &Layout, config: &Config, rng: &mut StdRng) -> (Map, (i32, i32), Vec<Rect>) {
+    let corridors = &config.corridors;
+    // Config::validate rejects bad sizes, but an empty range would make gen_range panic and a
+    // room without floor would put its center in the wall or off the map, so don't rely on it
+    // here.
+    let min_size = cmp::max(3, config.room_min_size);
+    let max_size = cmp::max(min_size, config.room_max_size);
     // Fill map with "unblocked" tiles.
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut map = vec![vec![Tile::wall(); layout.map_height as usize]; layout.map_width as usize];
 
     let mut rooms = vec![];
+    // which compound each room belongs to; rooms that were never merged are on their own
+    let mut compounds: Vec<usize> = vec![];
+    // the room each room was connected to when it was placed
+    let mut links: Vec<Option<usize>> = vec![];
     let mut starting_position = (0, 0);
-    for _ in 0..MAX_ROOMS {
+    for _ in 0..config.max_rooms {
         // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let w = rng.gen_range(min_size, max_size + 1);
+        let h = rng.gen_range(min_size, max_size + 1);
+        if w >= layout.map_width || h >= layout.map_height {
+            trace!(
+                "mapgen",
+                "Skipped a {}x{} room, it doesn't fit the map",
+                w,
+                h
+            );
+            continue;
+        }
         // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+        let x = rng.gen_range(0, layout.map_width - w);
+        let y = rng.gen_range(0, layout.map_height - h);
 
         let new_room = Rect::new(x, y, w, h);
         // run through the other rooms and see if they intersect with this one
-        let failed = rooms
+        let mut touched: Vec<usize> = rooms
             .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
-        if !failed {
-            // this means there are no intersections, so this room is valid
-            // "paint" it to the map's tiles
+            .zip(&compounds)
+            .filter(|(other_room, _)| new_room.intersects_with(other_room))
+            .map(|(_, &compound)| compound)
+            .collect();
+        touched.sort_unstable();
+        touched.dedup();
+        // an overlapping room may be merged with everything it touches, as long as the
+        // resulting compound stays small enough
+        let merged_size = compounds.iter().filter(|c| touched.contains(c)).count() + 1;
+        let merge = !touched.is_empty()
+            && merged_size <= MAX_MERGED_ROOMS
+            && rng.gen::<f32>() < ROOM_MERGE_CHANCE;
+        if !touched.is_empty() && !merge {
+            trace!(
+                "mapgen",
+                "Rejected {:?}, it intersects another room",
+                new_room
+            );
+        } else {
+            let compound = if merge {
+                debug!(
+                    "mapgen",
+                    "Merged room {} at {:?} into a compound of {} rooms",
+                    rooms.len(),
+                    new_room,
+                    merged_size
+                );
+                for c in compounds.iter_mut().filter(|c| touched.contains(c)) {
+                    *c = touched[0];
+                }
+                touched[0]
+            } else {
+                debug!("mapgen", "Placed room {} at {:?}", rooms.len(), new_room);
+                rooms.len()
+            };
+            // this room is valid, "paint" it to the map's tiles
             create_room(new_room, &mut map);
 
-            // center coordinates of the new room
+            // center coordinates of the new room. A merged room keeps its own center rather than
+            // the compound's: the middle of an L- or T-shaped compound can fall in solid rock,
+            // but the middle of each of its rooms is always floor.
             let (new_x, new_y) = new_room.center();
-            if rooms.is_empty() {
+            let link = if rooms.is_empty() {
                 // this is the first room, where the player starts
                 starting_position = (new_x, new_y);
+                None
             } else {
                 // all rooms after the first
-                // connect it to the previous room with a tunnel
+                // connect it to the nearest room placed so far with a tunnel, which keeps
+                // tunnels short instead of snaking back to wherever the last room landed
+                let nearest = nearest_room(&rooms, (new_x, new_y));
+                let other = rooms[nearest].center();
+                carve_corridor(other, (new_x, new_y), corridors.pick(rng), &mut map, rng);
+                Some(nearest)
+            };
+            rooms.push(new_room);
+            compounds.push(compound);
+            links.push(link);
+        }
+    }
+
+    // The tunnels above form a tree with a single route between any two rooms; tie some rooms
+    // that aren't directly joined together to make loops.
+    for (a, b) in loop_corridor_pairs(&links, config.loop_corridors, rng) {
+        debug!("mapgen", "Added a loop corridor from room {} to {}", a, b);
+        carve_corridor(
+            rooms[a].center(),
+            rooms[b].center(),
+            corridors.pick(rng),
+            &mut map,
+            rng,
+        );
+    }
+
+    let centers: Vec<(i32, i32)> = rooms.iter().map(Rect::center).collect();
+    for room in &rooms {
+        add_pillars(room, &mut map, starting_position, &centers, rng);
+    }
+    (map, starting_position, rooms)
+}
 
-                // center coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
+// The index of the room whose center is closest to `point`. There must be at least one room.
+fn nearest_room(rooms: &[Rect], point: (i32, i32)) -> usize {
+    let distance = |room: &Rect| geometry::distance(room.center(), point);
+    rooms
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+        .map(|(index, _)| index)
+        .unwrap()
+}
 
-                // draw a coin (random bool value -- either true or false)
-                if rand::random() {
-                    // first move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    // first move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
+// Up to `count` different pairs of rooms that weren't joined directly, given the room each one
+// was linked to when it was placed. A tree of three or more rooms always has such a pair, and
+// each pair closes a loop.
+fn loop_corridor_pairs(
+    links: &[Option<usize>],
+    count: usize,
+    rng: &mut StdRng,
+) -> Vec<(usize, usize)> {
+    let linked = |a: usize, b: usize| links[a] == Some(b) || links[b] == Some(a);
+    let mut unlinked: Vec<(usize, usize)> = (0..links.len())
+        .flat_map(|a| (a + 1..links.len()).map(move |b| (a, b)))
+        .filter(|&(a, b)| !linked(a, b))
+        .collect();
+    let mut pairs = vec![];
+    while pairs.len() < count && !unlinked.is_empty() {
+        // drawn without replacement, so no two loop corridors join the same rooms
+        let index = rng.gen_range(0, unlinked.len() as u32) as usize;
+        pairs.push(unlinked.swap_remove(index));
+    }
+    pairs
+}
+
+// Carve a perfect maze by recursive backtracking. Corridors run through the odd coordinates,
+// with the walls between them on the even ones. Afterwards some dead ends are opened into a
+// neighbouring corridor, so there is more than one way around.
+fn make_maze(layout: &Layout, rng: &mut StdRng) -> (Map, (i32, i32)) {
+    let (width, height) = (layout.map_width, layout.map_height);
+    let mut map = vec![vec![Tile::wall(); height as usize]; width as usize];
+    // a cell must leave the outer wall intact
+    let is_cell = |x: i32, y: i32| x >= 1 && x <= width - 2 && y >= 1 && y <= height - 2;
+    let directions = [(0, -2), (0, 2), (-2, 0), (2, 0)];
+
+    let start = (1, 1);
+    map.set_tile(1, 1, Tile::empty());
+    let mut stack = vec![start];
+    while let Some(&(x, y)) = stack.last() {
+        let unvisited: Vec<_> = directions
+            .iter()
+            .map(|&(dx, dy)| (x + dx, y + dy))
+            .filter(|&(nx, ny)| is_cell(nx, ny) && map.is_blocked(nx, ny))
+            .collect();
+        if unvisited.is_empty() {
+            stack.pop();
+            continue;
+        }
+        let (nx, ny) = unvisited[rng.gen_range(0, unvisited.len() as u32) as usize];
+        map.set_tile((x + nx) / 2, (y + ny) / 2, Tile::empty());
+        map.set_tile(nx, ny, Tile::empty());
+        stack.push((nx, ny));
+    }
+
+    let mut loops = 0;
+    for x in (1..width - 1).step_by(2) {
+        for y in (1..height - 1).step_by(2) {
+            let open = directions
+                .iter()
+                .filter(|&&(dx, dy)| !map.is_blocked(x + dx / 2, y + dy / 2))
+                .count();
+            if open != 1 || rng.gen::<f32>() >= MAZE_LOOP_CHANCE {
+                continue;
+            }
+            // knock through one of the walls that has a corridor behind it
+            let walls: Vec<_> = directions
+                .iter()
+                .filter(|&&(dx, dy)| {
+                    is_cell(x + dx, y + dy) && map.is_blocked(x + dx / 2, y + dy / 2)
+                })
+                .collect();
+            if !walls.is_empty() {
+                let (dx, dy) = walls[rng.gen_range(0, walls.len() as u32) as usize];
+                map.set_tile(x + dx / 2, y + dy / 2, Tile::empty());
+                loops += 1;
             }
-            rooms.push(new_room);
         }
     }
 
-    (map, starting_position)
+    info!(
+        "mapgen",
+        "Carved a maze with {} loops, the player starts at {:?}", loops, start
+    );
+    (map, start)
+}
+
+// Dig caverns with a single random walk from the middle of the map. The walker tends to keep
+// going straight, and stops once enough of the map is open; since it only ever carves next to
+// where it already is, every floor tile is connected to the start.
+fn make_caves(layout: &Layout, rng: &mut StdRng) -> (Map, (i32, i32)) {
+    let (width, height) = (layout.map_width, layout.map_height);
+    let mut map = vec![vec![Tile::wall(); height as usize]; width as usize];
+    let start = (width / 2, height / 2);
+    map.set_tile(start.0, start.1, Tile::empty());
+
+    let target = ((width - 2) * (height - 2)) as f32 * CAVE_FLOOR_FRACTION;
+    let directions = [(0, -1), (0, 1), (-1, 0), (1, 0)];
+    let (mut x, mut y) = start;
+    let mut direction = directions[rng.gen_range(0, directions.len() as u32) as usize];
+    let mut floor = 1;
+    while (floor as f32) < target {
+        if rng.gen::<f32>() < CAVE_TURN_CHANCE {
+            direction = directions[rng.gen_range(0, directions.len() as u32) as usize];
+        }
+        let (nx, ny) = (x + direction.0, y + direction.1);
+        // keep the outer edge of the map solid
+        if nx < 1 || nx >= width - 1 || ny < 1 || ny >= height - 1 {
+            direction = directions[rng.gen_range(0, directions.len() as u32) as usize];
+            continue;
+        }
+        x = nx;
+        y = ny;
+        if map.is_blocked(x, y) {
+            map.set_tile(x, y, Tile::empty());
+            floor += 1;
+        }
+    }
+
+    // Fill in one-tile spurs. A tile with a single open neighbour is a dead end, so filling it
+    // never cuts the rest of the cave off.
+    let mut eroded = 0;
+    for x in 1..width - 1 {
+        for y in 1..height - 1 {
+            if (x, y) == start || map.is_blocked(x, y) {
+                continue;
+            }
+            let open = directions
+                .iter()
+                .filter(|&&(dx, dy)| !map.is_blocked(x + dx, y + dy))
+                .count();
+            if open == 1 {
+                map.set_tile(x, y, Tile::wall());
+                eroded += 1;
+            }
+        }
+    }
+
+    info!(
+        "mapgen",
+        "Dug caves with {} floor tiles ({} spurs filled), the player starts at {:?}",
+        floor - eroded,
+        eroded,
+        start
+    );
+    (map, start)
 }
 
 // Generic object definition: player, monster, items, etc.
@@ -130,24 +1146,31 @@ struct Object {
     x: i32,
     y: i32,
     char: char,
+    name: String,
     color: Color,
 }
 
 impl Object {
-    pub fn new(x: i32, y: i32, char: char, color: Color) -> Self {
-        Object { x, y, char, color }
+    pub fn new(x: i32, y: i32, char: char, name: &str, color: Color) -> Self {
+        Object {
+            x,
+            y,
+            char,
+            name: name.into(),
+            color,
+        }
     }
 
     // Move by the given amount if the destination isn't blocked.
     pub fn move_by(&mut self, dx: i32, dy: i32, map: &Map) {
-        if !map[(self.x + dx) as usize][(self.y + dy) as usize].blocked {
+        if !map.is_blocked(self.x + dx, self.y + dy) {
             self.x += dx;
             self.y += dy;
         }
     }
 
     // Set the color and draw the character that represents this object at its position.
-    pub fn draw(&self, con: &mut tcod::Console) {
+    pub fn draw(&self, con: &mut dyn tcod::Console) {
         con.set_default_foreground(self.color);
         con.put_char(
             self.x,
@@ -158,156 +1181,1296 @@ impl Object {
     }
 }
 
-fn render_all(
-    root: &mut Root,
-    con: &mut Offscreen,
-    objects: &[Object],
-    map: &mut Map,
-    fov_map: &mut FovMap,
-    fov_recompute: bool,
-) {
-    // Set background color of all tiles.
-    if fov_recompute {
-        // Recompute FOV if needed
-        let player = &objects[0];
-        fov_map.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
-    }
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            let visible = fov_map.is_in_fov(x, y);
-            let wall = map[x as usize][y as usize].block_sight;
-            let color = match (visible, wall) {
-                // Outside field of view
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // Inside field of view
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
-            };
-            let explored = &mut map[x as usize][y as usize].explored;
-            if visible {
-                // Title is visible, so set explored to true
-                *explored = true;
+// Handle to an object in Objects. It never refers to a different object after the one it was
+// issued for is removed; lookups with it just fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ObjectId {
+    index: usize,
+    generation: u32,
+}
+
+struct Slot {
+    generation: u32,
+    object: Option<Object>,
+}
+
+// Every object on the level. Freed slots are reused with a bumped generation, which is what
+// makes old ObjectIds detectably stale.
+struct Objects {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+}
+
+impl Objects {
+    pub fn new() -> Self {
+        Objects {
+            slots: vec![],
+            free: vec![],
+        }
+    }
+
+    pub fn insert(&mut self, object: Object) -> ObjectId {
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index];
+            slot.object = Some(object);
+            ObjectId {
+                index,
+                generation: slot.generation,
             }
-            if *explored {
-                // Show explored tiles only
-                con.set_char_background(x, y, color, BackgroundFlag::Set);
+        } else {
+            self.slots.push(Slot {
+                generation: 0,
+                object: Some(object),
+            });
+            ObjectId {
+                index: self.slots.len() - 1,
+                generation: 0,
             }
         }
     }
-    // Draw all objects in the list.
-    for object in objects {
-        if fov_map.is_in_fov(object.x, object.y) {
-            object.draw(con);
+
+    // Not called yet; picking up items and killing monsters will.
+    #[allow(dead_code)]
+    pub fn remove(&mut self, id: ObjectId) -> Option<Object> {
+        let slot = self.slots.get_mut(id.index)?;
+        if slot.generation != id.generation {
+            return None;
         }
+        let object = slot.object.take()?;
+        slot.generation += 1;
+        self.free.push(id.index);
+        Some(object)
     }
-    // Blit the contents of the buffer to the root console.
-    blit(con, (0, 0), (MAP_WIDTH, MAP_HEIGHT), root, (0, 0), 1.0, 1.0);
-}
 
-fn main() {
-    let mut root = Root::initializer()
-        .font("arial10x10.png", FontLayout::Tcod)
-        .font_type(FontType::Greyscale)
-        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-        .title("Rust/libtcod tutorial")
-        .init();
-    tcod::system::set_fps(LIMIT_FPS);
-    let mut con = Offscreen::new(MAP_WIDTH, MAP_HEIGHT);
+    pub fn get(&self, id: ObjectId) -> Option<&Object> {
+        self.slots
+            .get(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.object.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: ObjectId) -> Option<&mut Object> {
+        self.slots
+            .get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.object.as_mut())
+    }
+
+    // Mutably borrow two different objects at once, e.g. an attacker and its target.
+    #[allow(dead_code)]
+    pub fn get_two_mut(
+        &mut self,
+        first: ObjectId,
+        second: ObjectId,
+    ) -> Option<(&mut Object, &mut Object)> {
+        if first.index == second.index {
+            return None;
+        }
+        self.get(first)?;
+        self.get(second)?;
+        let (low, high) = if first.index < second.index {
+            (first.index, second.index)
+        } else {
+            (second.index, first.index)
+        };
+        let (head, tail) = self.slots.split_at_mut(high);
+        let low_object = head[low].object.as_mut()?;
+        let high_object = tail[0].object.as_mut()?;
+        if first.index < second.index {
+            Some((low_object, high_object))
+        } else {
+            Some((high_object, low_object))
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjectId, &Object)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = ObjectId {
+                index,
+                generation: slot.generation,
+            };
+            slot.object.as_ref().map(|object| (id, object))
+        })
+    }
+}
+
+impl Index<ObjectId> for Objects {
+    type Output = Object;
+
+    fn index(&self, id: ObjectId) -> &Object {
+        self.get(id).expect("stale ObjectId")
+    }
+}
+
+impl IndexMut<ObjectId> for Objects {
+    fn index_mut(&mut self, id: ObjectId) -> &mut Object {
+        self.get_mut(id).expect("stale ObjectId")
+    }
+}
+
+// Recompute the FOV from the player if anything marked it dirty, and clear the mark. This is the
+// only place that reads or clears fov_dirty. Returns whether it recomputed.
+fn update_fov(fov: &mut FovMap, game: &mut Game, objects: &Objects) -> bool {
+    if !game.fov_dirty {
+        return false;
+    }
+    let player = &objects[game.player];
+    fov.compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+    game.fov_dirty = false;
+    true
+}
+
+fn render_all(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &Objects,
+    view: &View,
+    layout: &Layout,
+    palette: &Palette,
+) {
+    // Set background color of all tiles.
+    tcod.fov_recomputed = update_fov(&mut tcod.fov, game, objects);
+    if tcod.fov_recomputed {
+        let player = &objects[game.player];
+        tcod.fov_recomputes += 1;
+        trace!(
+            "fov",
+            "Recomputed FOV from ({}, {}), {} recomputes so far",
+            player.x,
+            player.y,
+            tcod.fov_recomputes
+        );
+    }
+    // Bounds come from the map itself; the color cache has to have been built from it.
+    let (width, height) = (game.map.len() as i32, game.map[0].len() as i32);
+    debug_assert_eq!(tcod.tile_colors.len(), game.map.len());
+    debug_assert_eq!(tcod.tile_colors[0].len(), game.map[0].len());
+    for y in 0..height {
+        for x in 0..width {
+            let visible = tcod.fov.is_in_fov(x, y);
+            let (dark, light) = tcod.tile_colors[x as usize][y as usize];
+            let color = if visible { light } else { dark };
+            let explored = &mut game.map[x as usize][y as usize].explored;
+            if visible {
+                // Title is visible, so set explored to true
+                *explored = true;
+            }
+            if *explored {
+                // Show explored tiles only
+                tcod.con
+                    .set_char_background(x, y, color, BackgroundFlag::Set);
+            }
+        }
+    }
+    // Highlight the path the player would walk if they clicked.
+    for &(x, y) in &view.path_preview {
+        tcod.con
+            .set_char_background(x, y, palette.path_preview, BackgroundFlag::Set);
+    }
+    if update_ghosts(game, objects, &tcod.fov) {
+        flush_input(&mut tcod.key_queue, game);
+    }
+    // Draw dimmed ghosts under the objects, only where the player can't see.
+    for ghost in game.ghosts.values() {
+        if !tcod.fov.is_in_fov(ghost.x, ghost.y) {
+            tcod.con.set_default_foreground(ghost_color(ghost, palette));
+            tcod.con
+                .put_char(ghost.x, ghost.y, ghost.char, BackgroundFlag::None);
+        }
+    }
+    // Draw all objects in the list.
+    for (_, object) in objects.iter() {
+        if tcod.fov.is_in_fov(object.x, object.y) {
+            object.draw(&mut tcod.con);
+        }
+    }
+    if view.show_overview {
+        // Replace the lit view with the explored layout.
+        tcod.con.clear();
+        render_overview(&mut tcod.con, &game.map, &objects[game.player], palette);
+    }
+    // Blit the contents of the buffer to the root console.
+    blit(
+        &tcod.con,
+        (0, 0),
+        (layout.map_width, layout.map_height),
+        &mut tcod.root,
+        (0, 0),
+        1.0,
+        1.0,
+    );
+
+    tcod.panel.set_default_background(palette.background);
+    tcod.panel.clear();
+    tcod.panel.set_default_foreground(palette.message);
+    let max_len = layout.minimap_x as usize;
+    if describe_player_tile(objects, game.player, max_len, &mut tcod.hud_text) {
+        tcod.panel.print(0, 0, &tcod.hud_text);
+    }
+    tcod.hud_text.clear();
+    // writing to a String can't fail
+    let _ = write!(tcod.hud_text, "Turn: {}", game.turn);
+    tcod.panel.print(0, 1, &tcod.hud_text);
+    tcod.hud_text.clear();
+    let _ = write!(tcod.hud_text, "Depth: {}", game.depth);
+    tcod.panel.print(0, 2, &tcod.hud_text);
+
+    // Print the latest messages between the HUD and the minimap, newest at the bottom.
+    let message_width = layout.minimap_x - 1 - MSG_X;
+    for (line, (message, color)) in game
+        .messages
+        .iter_newest()
+        .take(PANEL_HEIGHT as usize - 1)
+        .enumerate()
+    {
+        tcod.panel.set_default_foreground(*color);
+        tcod.panel.print_rect(
+            MSG_X,
+            PANEL_HEIGHT - 1 - line as i32,
+            message_width,
+            1,
+            message,
+        );
+    }
+    render_minimap(
+        &mut tcod.panel,
+        &game.map,
+        &objects[game.player],
+        layout,
+        palette,
+    );
+    blit(
+        &tcod.panel,
+        (0, 0),
+        (layout.screen_width, PANEL_HEIGHT),
+        &mut tcod.root,
+        (0, layout.panel_y),
+        1.0,
+        1.0,
+    );
+}
+
+// Say what else is on the player's tile, in at most max_len characters, writing it to `text`.
+// Names that don't fit are summarized as "...and N more". Returns false, leaving `text` empty,
+// when there is nothing else there.
+fn describe_player_tile(
+    objects: &Objects,
+    player_id: ObjectId,
+    max_len: usize,
+    text: &mut String,
+) -> bool {
+    text.clear();
+    let player = &objects[player_id];
+    // collecting nothing doesn't allocate, so the usual empty tile costs nothing
+    let names: Vec<&str> = objects
+        .iter()
+        .filter(|&(id, object)| id != player_id && (object.x, object.y) == (player.x, player.y))
+        .map(|(_, object)| object.name.as_str())
+        .collect();
+    if names.is_empty() {
+        return false;
+    }
+    let mut shown = names.len();
+    loop {
+        text.clear();
+        text.push_str("You see ");
+        for (i, name) in names[..shown].iter().enumerate() {
+            if i > 0 {
+                text.push_str(", ");
+            }
+            text.push_str(name);
+        }
+        if shown < names.len() {
+            let _ = write!(text, " ...and {} more", names.len() - shown);
+        }
+        text.push_str(" here.");
+        if text.len() <= max_len {
+            return true;
+        }
+        if shown == 1 {
+            let end = text
+                .char_indices()
+                .nth(max_len)
+                .map_or(text.len(), |(i, _)| i);
+            text.truncate(end);
+            return true;
+        }
+        shown -= 1;
+    }
+}
+
+// Each tile's background out of view and in view, for Tcod::tile_colors.
+fn tile_colors(map: &Map, palette: &Palette) -> Vec<Vec<(Color, Color)>> {
+    map.iter()
+        .map(|column| {
+            column
+                .iter()
+                .map(|tile| match tile.terrain {
+                    Terrain::Wall => (palette.dark_wall, palette.light_wall),
+                    Terrain::Floor => (palette.dark_ground, palette.light_ground),
+                    Terrain::Water => (palette.dark_water, palette.light_water),
+                    Terrain::Bridge => (palette.dark_bridge, palette.light_bridge),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Build the FOV map for a tile grid. Transparency and walkability come only from the tiles, so
+// this is the one place a FovMap is filled in.
+fn initialise_fov(map: &Map) -> FovMap {
+    let (width, height) = (map.len() as i32, map[0].len() as i32);
+    let mut fov = FovMap::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let tile = &map[x as usize][y as usize];
+            fov.set(x, y, !tile.block_sight, !tile.blocked);
+        }
+    }
+    fov
+}
+
+// A ghost's glyph color: the creature's own, faded toward the palette's background.
+fn ghost_color(ghost: &Ghost, palette: &Palette) -> Color {
+    colors::lerp(ghost.color, palette.background, GHOST_FADE)
+}
+
+// Remember where each visible creature is. A ghost is forgotten once its tile is seen without
+// the creature, or after GHOST_MEMORY_TURNS. It never follows the creature out of sight.
+// Returns whether anything came into view that wasn't in view last turn.
+fn update_ghosts(game: &mut Game, objects: &Objects, fov: &FovMap) -> bool {
+    let (turn, player) = (game.turn, game.player);
+    let mut spotted = false;
+    for (id, object) in objects.iter().filter(|&(id, _)| id != player) {
+        if fov.is_in_fov(object.x, object.y) {
+            // A ghost refreshed this turn or the last means the object was already in view.
+            spotted |= game
+                .ghosts
+                .get(&id)
+                .is_none_or(|ghost| ghost.turn + 1 < turn);
+            let ghost = Ghost {
+                x: object.x,
+                y: object.y,
+                char: object.char,
+                color: object.color,
+                turn,
+            };
+            game.ghosts.insert(id, ghost);
+        }
+    }
+    // Also forgets ghosts of objects that have been removed once their tile is seen.
+    game.ghosts.retain(|id, ghost| {
+        let still_there = objects
+            .get(*id)
+            .is_some_and(|object| (object.x, object.y) == (ghost.x, ghost.y));
+        let disproved = fov.is_in_fov(ghost.x, ghost.y) && !still_there;
+        !disproved && turn - ghost.turn <= GHOST_MEMORY_TURNS
+    });
+    spotted
+}
+
+// Something just came into view: drop the moves typed and the walk started before the player
+// could see it.
+fn flush_input(key_queue: &mut VecDeque<Key>, game: &mut Game) {
+    key_queue.clear();
+    game.travel.clear();
+}
+
+// Reads every pending event, so keys pressed in quick succession queue up instead of replacing
+// each other, and clicks aren't lost to later mouse movement. Keys past the queue limit are
+// dropped, except Escape, which always gets through.
+fn queue_events(
+    events: impl IntoIterator<Item = Event>,
+    mouse: &mut Mouse,
+    key_queue: &mut VecDeque<Key>,
+) {
+    for event in events {
+        match event {
+            Event::Mouse(moved) => {
+                let (lbutton, rbutton) = (mouse.lbutton_pressed, mouse.rbutton_pressed);
+                *mouse = moved;
+                mouse.lbutton_pressed |= lbutton;
+                mouse.rbutton_pressed |= rbutton;
+            }
+            Event::Key(key) => {
+                if key_queue.len() < INPUT_QUEUE_LIMIT || key.code == KeyCode::Escape {
+                    key_queue.push_back(key);
+                }
+            }
+        }
+    }
+}
+
+// The key to handle this frame: one per frame, oldest first.
+fn next_key(key_queue: &mut VecDeque<Key>) -> Key {
+    key_queue.pop_front().unwrap_or_default()
+}
+
+// Rolling average of recent frame times and how many heap allocations each frame made.
+struct FrameTimes {
+    samples: VecDeque<(Duration, usize)>,
+}
+
+impl FrameTimes {
+    pub fn new() -> Self {
+        FrameTimes {
+            samples: VecDeque::with_capacity(FRAME_SAMPLES),
+        }
+    }
+
+    pub fn record(&mut self, frame: Duration, allocations: usize) {
+        if self.samples.len() == FRAME_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((frame, allocations));
+    }
+
+    pub fn average_ms(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let total: Duration = self.samples.iter().map(|&(frame, _)| frame).sum();
+        total.as_secs_f32() * 1000.0 / self.samples.len() as f32
+    }
+
+    pub fn average_allocations(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let total: usize = self
+            .samples
+            .iter()
+            .map(|&(_, allocations)| allocations)
+            .sum();
+        total as f32 / self.samples.len() as f32
+    }
+}
+
+// Draw engine metrics and the latest logged warnings in the top left corner, over everything.
+fn render_debug_overlay(
+    tcod: &mut Tcod,
+    objects: &Objects,
+    game: &Game,
+    frame_times: &FrameTimes,
+    palette: &Palette,
+) {
+    let player = &objects[game.player];
+    let mut lines = vec![
+        format!("Frame: {:.1} ms", frame_times.average_ms()),
+        if alloc_count::ENABLED {
+            format!(
+                "Allocations: {:.0}/frame",
+                frame_times.average_allocations()
+            )
+        } else {
+            "Allocations: not counted in release builds".to_string()
+        },
+        format!(
+            "FOV recomputes: {}{}",
+            tcod.fov_recomputes,
+            if tcod.fov_recomputed {
+                " (this frame)"
+            } else {
+                ""
+            }
+        ),
+        format!("Objects: {}", objects.iter().count()),
+        format!("Player: ({}, {})", player.x, player.y),
+        format!("Seed: {}", game.seed),
+        format!(
+            "Level: {} floor, {} water, {} bridge",
+            game.level.floor_tiles, game.level.water_tiles, game.level.bridge_tiles
+        ),
+    ];
+    lines.extend(logging::recent_warnings());
+
+    tcod.root.set_default_background(palette.background);
+    tcod.root.set_default_foreground(palette.overlay_text);
+    for (y, line) in lines.iter().enumerate() {
+        tcod.root
+            .print_ex(0, y as i32, BackgroundFlag::Set, TextAlignment::Left, line);
+    }
+}
+
+// Recompute the path preview when the hovered tile or the player's position changes. The path
+// only crosses explored, walkable tiles, and there is none while the overview is shown.
+fn update_path_preview(
+    view: &mut View,
+    mouse: &Mouse,
+    map: &Map,
+    player: &Object,
+    layout: &Layout,
+) {
+    let (x, y) = (mouse.cx as i32, mouse.cy as i32);
+    let on_map = (0..layout.map_width).contains(&x) && (0..layout.map_height).contains(&y);
+    let key = if on_map && !view.show_overview {
+        Some(((player.x, player.y), (x, y)))
+    } else {
+        None
+    };
+    if key == view.preview_key {
+        return;
+    }
+    view.preview_key = key;
+    view.path_preview = match key {
+        Some((from, to)) if map.tile(x, y).is_some_and(|tile| tile.explored) => {
+            explored_path(map, from, to)
+        }
+        _ => vec![],
+    };
+}
+
+// Steps from `from` to `to` moving only in the four arrow-key directions through explored,
+// walkable tiles. Empty if there is no such path.
+fn explored_path(map: &Map, from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+    let width = map.len() as i32;
+    let height = map[0].len() as i32;
+    let walkable = |_from: (i32, i32), (x, y): (i32, i32)| match map.tile(x, y) {
+        Some(tile) if tile.explored && !tile.blocked => 1.0,
+        _ => 0.0,
+    };
+    // A diagonal cost of zero disables diagonal steps.
+    let mut astar = AStar::new_from_callback(width, height, walkable, 0.0);
+    if !astar.find(from, to) {
+        return vec![];
+    }
+    (0..astar.len())
+        .filter_map(|index| astar.get(index))
+        .collect()
+}
+
+// Color of an explored tile in the overview and minimap, or None if it hasn't been seen.
+fn overview_color(tile: &Tile, palette: &Palette) -> Option<Color> {
+    if !tile.explored {
+        return None;
+    }
+    match tile.terrain {
+        Terrain::Wall => Some(palette.overview_wall),
+        Terrain::Water => Some(palette.overview_water),
+        Terrain::Floor | Terrain::Bridge => Some(palette.overview_ground),
+    }
+}
 
-    // Generate map (not currently drawn to screen).
-    let (mut map, (player_x, player_y)) = make_map();
+// Draw every explored tile, one cell per tile, with the player marked.
+fn render_overview(con: &mut Offscreen, map: &Map, player: &Object, palette: &Palette) {
+    let (width, height) = (map.len() as i32, map[0].len() as i32);
+    for y in 0..height {
+        for x in 0..width {
+            if let Some(color) = overview_color(&map[x as usize][y as usize], palette) {
+                con.set_char_background(x, y, color, BackgroundFlag::Set);
+            }
+        }
+    }
+    con.set_char_background(
+        player.x,
+        player.y,
+        palette.overview_player,
+        BackgroundFlag::Set,
+    );
+}
+
+// Draw the explored neighborhood around the player in the right corner of the panel, as wide as
+// MINIMAP_SIZE and as tall as the panel.
+fn render_minimap(
+    panel: &mut Offscreen,
+    map: &Map,
+    player: &Object,
+    layout: &Layout,
+    palette: &Palette,
+) {
+    let left = layout.minimap_x;
+    let (width, height) = (map.len() as i32, map[0].len() as i32);
+    for dy in 0..PANEL_HEIGHT {
+        for dx in 0..MINIMAP_SIZE {
+            let x = player.x - MINIMAP_SIZE / 2 + dx;
+            let y = player.y - PANEL_HEIGHT / 2 + dy;
+            let in_map = (0..width).contains(&x) && (0..height).contains(&y);
+            let color = if (x, y) == (player.x, player.y) {
+                palette.overview_player
+            } else if in_map {
+                overview_color(&map[x as usize][y as usize], palette).unwrap_or(palette.background)
+            } else {
+                palette.background
+            };
+            panel.put_char(left + dx, dy, ' ', BackgroundFlag::None);
+            panel.set_char_background(left + dx, dy, color, BackgroundFlag::Set);
+        }
+    }
+}
+
+fn main() {
+    // Refuse to start with bad arguments or a console the layout can't fit in.
+    let (config, layout) = Config::from_args(std::env::args().skip(1))
+        .and_then(|config| {
+            let layout = Layout::new(config.screen_width, config.screen_height)?;
+            config.validate(&layout)?;
+            Ok((config, layout))
+        })
+        .unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        });
+    let palette = config.palette;
+    if let Err(error) = logging::init(&config.log_file, config.log_filter.clone()) {
+        eprintln!(
+            "Could not open log file {}: {}",
+            config.log_file.display(),
+            error
+        );
+    }
+    info!("main", "Starting with {:?}", layout);
+    for name in palette.low_contrast_entities(config.min_contrast) {
+        warn!(
+            "main",
+            "The {} color is hard to see on the ground, try --color {}=#RRGGBB", name, name
+        );
+    }
+
+    // A replayed trace brings its own seed.
+    let replay = config.verify.as_ref().map(|path| {
+        Trace::read(path).unwrap_or_else(|error| {
+            eprintln!("Could not read trace {}: {}", path.display(), error);
+            process::exit(1);
+        })
+    });
+    let seed = replay
+        .as_ref()
+        .map(|trace| trace.seed)
+        .or(config.seed)
+        // from two u32s: rand_core 0.4.0's next_u64 does a misaligned read that aborts in debug
+        // builds
+        .unwrap_or_else(|| {
+            u64::from(rand::random::<u32>()) << 32 | u64::from(rand::random::<u32>())
+        });
+    info!("main", "Map seed is {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
 
+    // Generate map (not currently drawn to screen), or load one from a dump along with where
+    // the other objects were.
+    let (map, (player_x, player_y), dumped_objects, depth) = match &config.load_map {
+        Some(path) => load_map(path, &layout)
+            .map(|level| (level.map, level.start, level.objects, level.depth))
+            .map_err(|error| format!("Could not load map {}: {}", path.display(), error)),
+        None => generate_map(&layout, &config, &mut rng)
+            .map(|(map, start)| (map, start, vec![], START_DEPTH))
+            .map_err(|error| error.to_string()),
+    }
+    .unwrap_or_else(|error| {
+        warn!("mapgen", "{}", error);
+        eprintln!("{}", error);
+        process::exit(1);
+    });
     // Create object representing the player.
-    let player = Object::new(player_x, player_y, '@', colors::WHITE);
+    let player = Object::new(player_x, player_y, '@', "player", palette.player);
 
     // Create object representing an NPC.
-    let npc = Object::new(
-        SCREEN_WIDTH / 2 - 5,
-        SCREEN_HEIGHT / 2,
-        '@',
-        tcod::colors::YELLOW,
-    );
+    let (npc_x, npc_y) = dumped_objects
+        .iter()
+        .find(|(name, _)| name == "stranger")
+        .map_or(
+            (layout.screen_width / 2 - 5, layout.screen_height / 2),
+            |&(_, position)| position,
+        );
+    let npc = Object::new(npc_x, npc_y, '@', "stranger", palette.npc);
 
     // List of objects
-    let mut objects = [player, npc];
-    let mut fov_map = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
-    for y in 0..MAP_HEIGHT {
-        for x in 0..MAP_WIDTH {
-            fov_map.set(
-                x,
-                y,
-                !map[x as usize][y as usize].block_sight,
-                !map[x as usize][y as usize].blocked,
-            );
-        }
+    let mut objects = Objects::new();
+    let player = objects.insert(player);
+    objects.insert(npc);
+    let level = LevelMetadata::survey(&map);
+    info!("mapgen", "Level contents: {:?}", level);
+    let mut game = Game {
+        map,
+        level,
+        seed,
+        depth,
+        player,
+        ghosts: HashMap::new(),
+        turn: 0,
+        messages: Messages::new(),
+        travel: VecDeque::new(),
+        fov_dirty: true,
+    };
+    if config.dump_map {
+        let listed: Vec<&Object> = objects.iter().map(|(_, object)| object).collect();
+        print!(
+            "{}",
+            dump_map(&game.map, seed, game.depth, config.generator, &listed)
+        );
+        process::exit(0);
+    }
+    for feeling in game.level.feelings() {
+        game.messages.add(feeling, palette.message);
+    }
+
+    if let Some(trace) = &replay {
+        process::exit(verify_trace(
+            trace,
+            &mut game,
+            &mut objects,
+            &config,
+            &layout,
+        ));
     }
-    let mut previous_player_position = (-1, -1);
+    let mut recorder = config.record.as_ref().and_then(|path| {
+        let recorder =
+            Recorder::create(path, seed, config.trace_hash(&layout)).and_then(|mut recorder| {
+                let checksum = state_checksum(&game, &objects, config.generator);
+                recorder.record(Entry::Check { turn: 0, checksum })?;
+                Ok(recorder)
+            });
+        match recorder {
+            Ok(recorder) => Some(recorder),
+            Err(error) => {
+                eprintln!("Could not record to {}: {}", path.display(), error);
+                None
+            }
+        }
+    });
+
+    let root = Root::initializer()
+        .font("arial10x10.png", FontLayout::Tcod)
+        .font_type(FontType::Greyscale)
+        .size(layout.screen_width, layout.screen_height)
+        .title("Rust/libtcod tutorial")
+        .init();
+    tcod::system::set_fps(LIMIT_FPS);
+    let mut tcod = Tcod {
+        root,
+        con: Offscreen::new(layout.map_width, layout.map_height),
+        panel: Offscreen::new(layout.screen_width, PANEL_HEIGHT),
+        fov: initialise_fov(&game.map),
+        tile_colors: tile_colors(&game.map, &palette),
+        fov_recomputes: 0,
+        fov_recomputed: false,
+        key: Default::default(),
+        key_queue: VecDeque::new(),
+        mouse: Default::default(),
+        hud_text: String::new(),
+    };
+    // Cleared to the palette's background every frame, so unexplored tiles show it.
+    tcod.con.set_default_background(palette.background);
+
+    let mut view = View {
+        show_overview: false,
+        show_debug_overlay: false,
+        screenshot_requested: false,
+        bug_report_requested: false,
+        preview_key: None,
+        path_preview: vec![],
+    };
+
+    let mut frame_times = FrameTimes::new();
+
+    while !tcod.root.window_closed() {
+        let frame_start = Instant::now();
+        let allocations_start = alloc_count::allocations();
+
+        // Poll for input without blocking, so the mouse is tracked between keypresses.
+        let events = std::iter::from_fn(|| {
+            input::check_for_event(input::MOUSE | input::KEY_PRESS).map(|(_, event)| event)
+        });
+        queue_events(events, &mut tcod.mouse, &mut tcod.key_queue);
+        update_path_preview(
+            &mut view,
+            &tcod.mouse,
+            &game.map,
+            &objects[game.player],
+            &layout,
+        );
 
-    while !root.window_closed() {
         // clear the screen of previous frame data.
-        con.clear();
+        tcod.con.clear();
 
         // render each object in the list into the offscreen buffer
-        let fov_recompute = previous_player_position != (objects[0].x, objects[0].y);
-        render_all(
-            &mut root,
-            &mut con,
-            &objects,
-            &mut map,
-            &mut fov_map,
-            fov_recompute,
-        );
+        render_all(&mut tcod, &mut game, &objects, &view, &layout, &palette);
+        // The overlay's own allocations are left out of the count it shows.
+        let mut overlay_allocations = 0;
+        if view.shows_debug_overlay(&config) {
+            let overlay_start = alloc_count::allocations();
+            render_debug_overlay(&mut tcod, &objects, &game, &frame_times, &palette);
+            overlay_allocations = alloc_count::allocations() - overlay_start;
+        }
 
-        root.flush();
+        tcod.root.flush();
 
-        // Handle keys and exit game if needed.
-        let player = &mut objects[0];
-        previous_player_position = (player.x, player.y);
-        let exit = handle_keys(&mut root, player, &map);
-        if exit {
-            break;
+        if view.screenshot_requested {
+            view.screenshot_requested = false;
+            take_screenshot(&mut game, &config);
+        }
+        if view.bug_report_requested {
+            view.bug_report_requested = false;
+            take_bug_report(&mut game, &objects, &config, &mut recorder);
+        }
+
+        // Handle keys and exit game if needed. The key is only taken from the queue now, after
+        // rendering, so a move queued before something came into view has been flushed.
+        tcod.key = next_key(&mut tcod.key_queue);
+        let before = (objects[game.player].x, objects[game.player].y);
+        let action = handle_keys(&mut tcod, &mut game, &mut objects, &mut view, &config);
+        match action {
+            PlayerAction::Exit => break,
+            PlayerAction::TookTurn => {
+                game.turn += 1;
+                let player = &objects[game.player];
+                let step = (player.x - before.0, player.y - before.1);
+                record_turn(&mut recorder, &game, &objects, step, &config);
+            }
+            PlayerAction::DidntTakeTurn => {}
+        }
+        frame_times.record(
+            frame_start.elapsed(),
+            alloc_count::allocations() - allocations_start - overlay_allocations,
+        );
+    }
+
+    // End the trace with the final state, so a replay checks the last few turns too.
+    if let Some(recorder) = &mut recorder {
+        let checksum = state_checksum(&game, &objects, config.generator);
+        let turn = game.turn;
+        if let Err(error) = recorder
+            .record(Entry::Check { turn, checksum })
+            .and_then(|_| recorder.flush())
+        {
+            warn!("main", "Could not finish the trace: {}", error);
         }
     }
 }
 
-fn handle_keys(root: &mut Root, player: &mut Object, map: &Map) -> bool {
-    use tcod::input::Key;
-    use tcod::input::KeyCode;
+fn handle_keys(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &mut Objects,
+    view: &mut View,
+    config: &Config,
+) -> PlayerAction {
+    use PlayerAction::*;
 
-    let key = root.wait_for_keypress(true);
-    match key {
+    let player = &mut objects[game.player];
+    if tcod.key.code == KeyCode::NoKey {
+        return handle_mouse(tcod, player, game, view);
+    }
+    // Any keypress interrupts a click-to-move walk.
+    game.travel.clear();
+    match tcod.key {
         Key {
             code: KeyCode::Enter,
             alt: true,
             ..
         } => {
             // Alt + Enter: toggle fullscreen
-            let fullscreen = root.is_fullscreen();
-            root.set_fullscreen(!fullscreen);
+            let fullscreen = tcod.root.is_fullscreen();
+            tcod.root.set_fullscreen(!fullscreen);
+            DidntTakeTurn
         }
         Key {
             code: KeyCode::Escape,
             ..
-        } => return true,
+        } => Exit,
+        Key {
+            code: KeyCode::Tab, ..
+        } => {
+            // Tab: toggle the map overview
+            view.show_overview = !view.show_overview;
+            DidntTakeTurn
+        }
+        Key {
+            code: KeyCode::F3, ..
+        } if config.debug => {
+            // F3: toggle the debug overlay
+            view.show_debug_overlay = !view.show_debug_overlay;
+            DidntTakeTurn
+        }
+        Key {
+            code: KeyCode::F10, ..
+        } if config.debug => {
+            // F10: save a bug report once the next frame is drawn
+            view.bug_report_requested = true;
+            DidntTakeTurn
+        }
+        Key {
+            code: KeyCode::F11, ..
+        } if config.debug => {
+            // F11: dump the map to a text file
+            let dumped = objects.iter().map(|(_, object)| object).collect::<Vec<_>>();
+            let text = dump_map(&game.map, game.seed, game.depth, config.generator, &dumped);
+            match save_map_dump(&config.screenshot_dir, &text) {
+                Ok(path) => game.messages.add(
+                    format!("Map saved to {}", path.display()),
+                    config.palette.message,
+                ),
+                Err(error) => {
+                    warn!("main", "Could not save map dump: {}", error);
+                    game.messages.add(
+                        format!("Could not save map dump: {}", error),
+                        config.palette.message,
+                    )
+                }
+            }
+            DidntTakeTurn
+        }
+        Key {
+            code: KeyCode::F12, ..
+        } => {
+            // F12: save a screenshot once the next frame is drawn
+            view.screenshot_requested = true;
+            DidntTakeTurn
+        }
         Key {
             code: KeyCode::Up, ..
-        } => player.move_by(0, -1, map),
+        } => {
+            move_player(player, 0, -1, game);
+            TookTurn
+        }
         Key {
             code: KeyCode::Down,
             ..
-        } => player.move_by(0, 1, map),
+        } => {
+            move_player(player, 0, 1, game);
+            TookTurn
+        }
         Key {
             code: KeyCode::Left,
             ..
-        } => player.move_by(-1, 0, map),
+        } => {
+            move_player(player, -1, 0, game);
+            TookTurn
+        }
         Key {
             code: KeyCode::Right,
             ..
-        } => player.move_by(1, 0, map),
-        _ => {}
+        } => {
+            move_player(player, 1, 0, game);
+            TookTurn
+        }
+        _ => DidntTakeTurn,
+    }
+}
+
+// Move the player, marking the FOV for recomputation if they actually moved.
+fn move_player(player: &mut Object, dx: i32, dy: i32, game: &mut Game) {
+    let before = (player.x, player.y);
+    player.move_by(dx, dy, &game.map);
+    game.fov_dirty |= (player.x, player.y) != before;
+}
+
+// Left click starts walking the previewed path and right click cancels the walk. While walking,
+// take one step per turn, stopping if the next step is no longer walkable.
+fn handle_mouse(
+    tcod: &mut Tcod,
+    player: &mut Object,
+    game: &mut Game,
+    view: &View,
+) -> PlayerAction {
+    if tcod.mouse.rbutton_pressed {
+        game.travel.clear();
+    } else if tcod.mouse.lbutton_pressed {
+        game.travel = view.path_preview.iter().cloned().collect();
+    }
+    // Each click is handled once, not on every frame until the mouse moves again.
+    tcod.mouse.lbutton_pressed = false;
+    tcod.mouse.rbutton_pressed = false;
+
+    match game.travel.pop_front() {
+        Some((x, y)) if !game.map.is_blocked(x, y) => {
+            let (dx, dy) = (x - player.x, y - player.y);
+            move_player(player, dx, dy, game);
+            PlayerAction::TookTurn
+        }
+        Some(_) => {
+            game.travel.clear();
+            PlayerAction::DidntTakeTurn
+        }
+        None => PlayerAction::DidntTakeTurn,
+    }
+}
+
+// Save a screenshot of the last flushed frame and report where it went.
+fn take_screenshot(game: &mut Game, config: &Config) {
+    match save_screenshot(&config.screenshot_dir) {
+        Ok(path) => {
+            info!("main", "Saved screenshot {}", path.display());
+            game.messages.add(
+                format!("Screenshot saved to {}", path.display()),
+                config.palette.message,
+            )
+        }
+        Err(error) => {
+            warn!("main", "Could not save screenshot: {}", error);
+            game.messages.add(
+                format!("Could not save screenshot: {}", error),
+                config.palette.message,
+            )
+        }
+    }
+}
+
+fn millis_since_epoch() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or(0)
+}
+
+// A checksum of everything a replay has to reproduce: the turn, the map and where every object
+// is.
+fn state_checksum(game: &Game, objects: &Objects, generator: Generator) -> u64 {
+    let listed: Vec<&Object> = objects.iter().map(|(_, object)| object).collect();
+    let map = dump_map(&game.map, game.seed, game.depth, generator, &listed);
+    trace::fnv1a(format!("{}\n{}", game.turn, map).as_bytes())
+}
+
+// Add the turn just taken to the trace, with a checksum every TRACE_CHECK_INTERVAL turns. A
+// trace that can't be written is dropped rather than interrupting the game.
+fn record_turn(
+    recorder: &mut Option<Recorder>,
+    game: &Game,
+    objects: &Objects,
+    (dx, dy): (i32, i32),
+    config: &Config,
+) {
+    let result = match recorder {
+        Some(recorder) => {
+            let turn = game.turn;
+            recorder.record(Entry::Move { turn, dx, dy }).and_then(|_| {
+                if turn.is_multiple_of(TRACE_CHECK_INTERVAL) {
+                    let checksum = state_checksum(game, objects, config.generator);
+                    recorder.record(Entry::Check { turn, checksum })
+                } else {
+                    Ok(())
+                }
+            })
+        }
+        None => return,
+    };
+    if let Err(error) = result {
+        warn!("main", "Stopped recording the trace: {}", error);
+        *recorder = None;
+    }
+}
+
+// Replay a trace's moves on the freshly generated game, checking every checksum on the way.
+// Returns the process exit code: 0 if the replay matched throughout.
+fn verify_trace(
+    trace: &Trace,
+    game: &mut Game,
+    objects: &mut Objects,
+    config: &Config,
+    layout: &Layout,
+) -> i32 {
+    if trace.config != config.trace_hash(layout) {
+        eprintln!(
+            "The trace was recorded with different settings, \
+             pass the same size and map generation options"
+        );
+        return 1;
+    }
+    for entry in &trace.entries {
+        match *entry {
+            Entry::Move { turn, dx, dy } => {
+                let player = &mut objects[game.player];
+                move_player(player, dx, dy, game);
+                game.turn = turn;
+            }
+            Entry::Check { turn, checksum } => {
+                if state_checksum(game, objects, config.generator) != checksum {
+                    eprintln!("The replay diverged from the trace by turn {}", turn);
+                    return 1;
+                }
+            }
+        }
+    }
+    println!("The replay matched the trace through turn {}", game.turn);
+    0
+}
+
+fn take_bug_report(
+    game: &mut Game,
+    objects: &Objects,
+    config: &Config,
+    recorder: &mut Option<Recorder>,
+) {
+    match save_bug_report(game, objects, config, recorder) {
+        Ok(path) => {
+            info!("main", "Saved bug report {}", path.display());
+            game.messages.add(
+                format!("Bug report saved to {}", path.display()),
+                config.palette.message,
+            )
+        }
+        Err(error) => {
+            warn!("main", "Could not save bug report: {}", error);
+            game.messages.add(
+                format!("Could not save bug report: {}", error),
+                config.palette.message,
+            )
+        }
+    }
+}
+
+// Gather what it takes to reproduce a problem into a directory named after the current time:
+// a screenshot, a map dump and, with --record, the trace so far.
+fn save_bug_report(
+    game: &Game,
+    objects: &Objects,
+    config: &Config,
+    recorder: &mut Option<Recorder>,
+) -> io::Result<PathBuf> {
+    let dir = Path::new(BUG_REPORT_DIR).join(millis_since_epoch().to_string());
+    fs::create_dir_all(&dir)?;
+    write_screenshot(&dir.join("screenshot.png"))?;
+    let listed: Vec<&Object> = objects.iter().map(|(_, object)| object).collect();
+    fs::write(
+        dir.join("map.txt"),
+        dump_map(&game.map, game.seed, game.depth, config.generator, &listed),
+    )?;
+    if let (Some(recorder), Some(path)) = (recorder, &config.record) {
+        recorder.flush()?;
+        fs::copy(path, dir.join("trace.txt"))?;
+    }
+    Ok(dir)
+}
+
+// Save the root console to a PNG named after the current time, creating the directory if needed.
+fn save_screenshot(dir: &Path) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let millis = millis_since_epoch();
+    let path = dir.join(format!("screenshot-{}.png", millis));
+    write_screenshot(&path)?;
+    Ok(path)
+}
+
+// Save the root console to a PNG. libtcod doesn't report failures, so check the file is there.
+fn write_screenshot(path: &Path) -> io::Result<()> {
+    tcod::system::save_screenshot(path);
+    if !path.exists() {
+        return Err(io::Error::other(format!(
+            "{} was not written",
+            path.display()
+        )));
     }
-    false
+    Ok(())
+}
+
+// Write the map dump next to the screenshots, named after the current time.
+fn save_map_dump(dir: &Path, text: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let millis = millis_since_epoch();
+    let path = dir.join(format!("map-{}.txt", millis));
+    fs::write(&path, text)?;
+    Ok(path)
+}
+
+// The map as text: a header line, one line per row of terrain ('#' wall, '.' floor, '~' water,
+// '=' bridge), then a blank line and an `object <x> <y> <name>` line for each object. Objects
+// are listed apart from the grid so the terrain under them survives a round trip.
+fn dump_map(map: &Map, seed: u64, depth: u32, generator: Generator, objects: &[&Object]) -> String {
+    let (width, height) = (map.len() as i32, map[0].len() as i32);
+    let mut text = format!(
+        "seed={} generator={} depth={}\n",
+        seed,
+        generator.name(),
+        depth
+    );
+    for y in 0..height {
+        for x in 0..width {
+            text.push(match map[x as usize][y as usize].terrain {
+                Terrain::Wall => '#',
+                Terrain::Floor => '.',
+                Terrain::Water => '~',
+                Terrain::Bridge => '=',
+            });
+        }
+        text.push('\n');
+    }
+    text.push('\n');
+    for object in objects {
+        text.push_str(&format!(
+            "object {} {} {}\n",
+            object.x, object.y, object.name
+        ));
+    }
+    text
+}
+
+// A level read back from a map dump.
+struct DumpedLevel {
+    map: Map,
+    // Where the player stands, like the start make_map returns.
+    start: (i32, i32),
+    // Where each other object stands, by name.
+    objects: Vec<(String, (i32, i32))>,
+    depth: u32,
+}
+
+// Rebuild the level from a dump.
+fn map_from_dump(text: &str) -> Result<DumpedLevel, String> {
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or("");
+    let depth = header
+        .split(' ')
+        .find_map(|field| field.strip_prefix("depth="))
+        .and_then(|depth| depth.parse().ok())
+        .ok_or_else(|| format!("invalid header '{}'", header))?;
+    let rows: Vec<&str> = lines.by_ref().take_while(|line| !line.is_empty()).collect();
+    let width = rows.first().map_or(0, |row| row.chars().count());
+    if width == 0 {
+        return Err("the dump has no map rows".to_string());
+    }
+    let mut map = vec![vec![Tile::wall(); rows.len()]; width];
+    for (y, row) in rows.iter().enumerate() {
+        if row.chars().count() != width {
+            return Err(format!("row {} is not {} tiles wide", y + 1, width));
+        }
+        for (x, c) in row.chars().enumerate() {
+            map[x][y] = match c {
+                '#' => Tile::wall(),
+                '.' => Tile::empty(),
+                '~' => Tile::water(),
+                '=' => Tile::bridge(),
+                c => return Err(format!("unknown tile '{}' at {}, {}", c, x, y)),
+            };
+        }
+    }
+
+    let mut start = None;
+    let mut objects = vec![];
+    // blank lines are skipped, so a hand-edited dump may end with a few
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.splitn(4, ' ').collect();
+        let position = match fields.as_slice() {
+            ["object", x, y, _] => x.parse().ok().zip(y.parse().ok()),
+            _ => None,
+        };
+        let (x, y) = position.ok_or_else(|| format!("invalid object line '{}'", line))?;
+        if map.tile(x, y).is_none() {
+            return Err(format!("object at {}, {} is off the map", x, y));
+        }
+        match fields[3] {
+            "player" => start = Some((x, y)),
+            name => objects.push((name.to_string(), (x, y))),
+        }
+    }
+    let start = start.ok_or_else(|| "the dump has no player".to_string())?;
+    Ok(DumpedLevel {
+        map,
+        start,
+        objects,
+        depth,
+    })
+}
+
+fn load_map(path: &Path, layout: &Layout) -> Result<DumpedLevel, String> {
+    let text = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let level = map_from_dump(&text)?;
+    let size = (level.map.len() as i32, level.map[0].len() as i32);
+    if size != (layout.map_width, layout.map_height) {
+        return Err(format!(
+            "the map is {}x{} but this console fits {}x{}",
+            size.0, size.1, layout.map_width, layout.map_height
+        ));
+    }
+    Ok(level)
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -345,19 +2508,1098 @@ impl Rect {
 fn create_room(room: Rect, map: &mut Map) {
     for x in (room.x1 + 1)..room.x2 {
         for y in (room.y1 + 1)..room.y2 {
-            map[x as usize][y as usize] = Tile::empty();
+            map.set_tile(x, y, Tile::empty());
         }
     }
 }
 
+// Dig a tunnel between two points. Every style reaches both ends; wide tunnels keep their
+// second line inside the map's outer wall.
+fn carve_corridor(
+    from: (i32, i32),
+    to: (i32, i32),
+    style: CorridorStyle,
+    map: &mut Map,
+    rng: &mut StdRng,
+) {
+    // draw a coin (random bool value -- either true or false)
+    let horizontal_first = rng.gen();
+    match style {
+        CorridorStyle::Narrow => carve_l_tunnel(from, to, horizontal_first, map),
+        CorridorStyle::Wide => {
+            carve_l_tunnel(from, to, horizontal_first, map);
+            // a parallel copy one tile over, or back if that would hit the edge
+            let width = map.len() as i32;
+            let height = map[0].len() as i32;
+            let shift = |v: i32, max: i32| if v + 1 < max - 1 { v + 1 } else { v - 1 };
+            let from = (shift(from.0, width), shift(from.1, height));
+            let to = (shift(to.0, width), shift(to.1, height));
+            carve_l_tunnel(from, to, horizontal_first, map);
+        }
+        CorridorStyle::Stepped => {
+            // each step goes along whichever axis is further from the target, with a bit of
+            // wobble, so the tunnel runs diagonally as a staircase of floor tiles
+            let (mut x, mut y) = from;
+            map.set_tile(x, y, Tile::empty());
+            while (x, y) != to {
+                let (dx, dy) = ((to.0 - x).abs(), (to.1 - y).abs());
+                if dy == 0 || (dx > 0 && rng.gen_range(0, dx + dy) < dx) {
+                    x += (to.0 - x).signum();
+                } else {
+                    y += (to.1 - y).signum();
+                }
+                map.set_tile(x, y, Tile::empty());
+            }
+        }
+    }
+}
+
+fn carve_l_tunnel(from: (i32, i32), to: (i32, i32), horizontal_first: bool, map: &mut Map) {
+    if horizontal_first {
+        // first move horizontally, then vertically
+        create_h_tunnel(from.0, to.0, from.1, map);
+        create_v_tunnel(from.1, to.1, to.0, map);
+    } else {
+        // first move vertically, then horizontally
+        create_v_tunnel(from.1, to.1, from.0, map);
+        create_h_tunnel(from.0, to.0, to.1, map);
+    }
+}
+
 fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
     for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+        map.set_tile(x, y, Tile::empty());
     }
 }
 
 fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
     for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+        map.set_tile(x, y, Tile::empty());
+    }
+}
+
+// Scatter pillars inside a room, more in bigger rooms. They stay off the room's center row and
+// column, where tunnels come in, and off the centers of any rooms merged with it, and any pillar
+// that would cut off part of the level is taken back out.
+fn add_pillars(
+    room: &Rect,
+    map: &mut Map,
+    start: (i32, i32),
+    centers: &[(i32, i32)],
+    rng: &mut StdRng,
+) {
+    let (width, height) = (room.x2 - room.x1 - 1, room.y2 - room.y1 - 1);
+    if width < PILLAR_MIN_ROOM_SIZE || height < PILLAR_MIN_ROOM_SIZE {
+        return;
+    }
+    let (center_x, center_y) = room.center();
+    let mut placed = 0;
+    for _ in 0..width * height / ROOM_AREA_PER_PILLAR {
+        // keep a free ring around the inside of the walls so doorways stay open
+        let x = rng.gen_range(room.x1 + 2, room.x2 - 1);
+        let y = rng.gen_range(room.y1 + 2, room.y2 - 1);
+        if x == center_x || y == center_y || centers.contains(&(x, y)) || map.is_blocked(x, y) {
+            continue;
+        }
+        map.set_tile(x, y, Tile::wall());
+        if all_reachable(map, &reachable_tiles(map, start)) {
+            placed += 1;
+        } else {
+            map.set_tile(x, y, Tile::empty());
+        }
+    }
+    trace!("mapgen", "Placed {} pillars in {:?}", placed, room);
+}
+
+// Run a river from the left edge of the map to the right, drifting up and down as it goes. It
+// floods only floor tiles, so it shows up where it crosses rooms and corridors and never opens
+// sight lines through rock. Where it cuts the level in two, bridges are laid across it; if that
+// can't reconnect everything, the river is dropped.
+fn add_river(map: &mut Map, start: (i32, i32), rng: &mut StdRng) {
+    let width = map.len() as i32;
+    let height = map[0].len() as i32;
+    // too short for a river between the top and bottom walls
+    if height < RIVER_WIDTH + 2 {
+        return;
+    }
+    let original = map.clone();
+
+    // Flooded tiles, grouped by column so a bridge can span the whole width of the river.
+    let mut columns: Vec<Vec<(i32, i32)>> = vec![];
+    let mut y = rng.gen_range(1, height - RIVER_WIDTH);
+    let mut drift = 0;
+    for x in 0..width {
+        if rng.gen::<f32>() < RIVER_TURN_CHANCE {
+            drift = rng.gen_range(-1, 2);
+        }
+        y = (y + drift).max(1).min(height - 1 - RIVER_WIDTH);
+        let mut flooded = vec![];
+        for y in y..y + RIVER_WIDTH {
+            if map.tile(x, y).map(|tile| tile.terrain) == Some(Terrain::Floor) {
+                map.set_tile(x, y, Tile::water());
+                flooded.push((x, y));
+            }
+        }
+        if !flooded.is_empty() {
+            columns.push(flooded);
+        }
+    }
+
+    // Bridge one crossing at a time until every floor tile can be reached from the start.
+    let mut bridges = 0;
+    loop {
+        if map.is_blocked(start.0, start.1) {
+            break;
+        }
+        let reachable = reachable_tiles(map, start);
+        if all_reachable(map, &reachable) {
+            debug!("mapgen", "Added a river with {} bridges", bridges);
+            return;
+        }
+        let crossing = columns.iter().find(|column| {
+            let top = column[0];
+            let bottom = column[column.len() - 1];
+            let ends = [(top.0, top.1 - 1), (bottom.0, bottom.1 + 1)];
+            let walkable = ends.iter().all(|&(x, y)| !map.is_blocked(x, y));
+            let reached = ends
+                .iter()
+                .filter(|&&(x, y)| reachable[x as usize][y as usize])
+                .count();
+            walkable && reached == 1
+        });
+        match crossing {
+            Some(column) => {
+                for &(x, y) in column {
+                    map.set_tile(x, y, Tile::bridge());
+                }
+                bridges += 1;
+            }
+            None => break,
+        }
+    }
+    debug!("mapgen", "Dropped a river that couldn't be bridged");
+    *map = original;
+}
+
+// Which tiles can be walked to from `start`, moving in the four arrow-key directions.
+fn reachable_tiles(map: &Map, start: (i32, i32)) -> Vec<Vec<bool>> {
+    let width = map.len() as i32;
+    let height = map[0].len() as i32;
+    let mut reachable = vec![vec![false; height as usize]; width as usize];
+    let mut frontier = vec![start];
+    reachable[start.0 as usize][start.1 as usize] = true;
+    while let Some((x, y)) = frontier.pop() {
+        for &(dx, dy) in &[(0, -1), (0, 1), (-1, 0), (1, 0)] {
+            let (nx, ny) = (x + dx, y + dy);
+            if (0..width).contains(&nx)
+                && (0..height).contains(&ny)
+                && !map.is_blocked(nx, ny)
+                && !reachable[nx as usize][ny as usize]
+            {
+                reachable[nx as usize][ny as usize] = true;
+                frontier.push((nx, ny));
+            }
+        }
+    }
+    reachable
+}
+
+// The connectivity check for generated levels: every walkable tile is in `reachable`.
+fn all_reachable(map: &Map, reachable: &[Vec<bool>]) -> bool {
+    map.iter().zip(reachable).all(|(column, reached)| {
+        column
+            .iter()
+            .zip(reached)
+            .all(|(tile, &r)| tile.blocked || r)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_map(width: i32, height: i32) -> Map {
+        vec![vec![Tile::wall(); height as usize]; width as usize]
+    }
+
+    fn connected(map: &Map, from: (i32, i32), to: (i32, i32)) -> bool {
+        reachable_tiles(map, from)[to.0 as usize][to.1 as usize]
+    }
+
+    #[test]
+    fn every_corridor_style_connects_its_endpoints() {
+        let (width, height) = (20, 12);
+        let endpoints = [
+            ((2, 2), (15, 9)),
+            ((15, 2), (2, 9)),
+            ((5, 5), (5, 5)),
+            ((3, 6), (16, 6)),
+            ((8, 1), (8, 10)),
+        ];
+        for &style in &[
+            CorridorStyle::Narrow,
+            CorridorStyle::Wide,
+            CorridorStyle::Stepped,
+        ] {
+            for seed in 0..20 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                for &(from, to) in &endpoints {
+                    let mut map = solid_map(width, height);
+                    carve_corridor(from, to, style, &mut map, &mut rng);
+                    assert!(
+                        connected(&map, from, to),
+                        "{:?} corridor from {:?} to {:?}, seed {}",
+                        style,
+                        from,
+                        to,
+                        seed
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wide_corridors_stay_connected_at_the_map_edge() {
+        // Next to the right or bottom wall, `shift` moves the second line back instead of
+        // forward, so its two ends can shift in opposite directions.
+        let (width, height) = (20, 12);
+        let edge_points = [
+            (1, 1),
+            (width - 2, 1),
+            (1, height - 2),
+            (width - 2, height - 2),
+            (width - 2, 5),
+            (7, height - 2),
+            (width - 3, height - 3),
+        ];
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for &from in &edge_points {
+                for &to in &edge_points {
+                    let mut map = solid_map(width, height);
+                    carve_corridor(from, to, CorridorStyle::Wide, &mut map, &mut rng);
+                    assert!(
+                        connected(&map, from, to),
+                        "wide corridor from {:?} to {:?}, seed {}",
+                        from,
+                        to,
+                        seed
+                    );
+                    // the outer wall stays solid
+                    for x in 0..width {
+                        assert!(map.is_blocked(x, 0) && map.is_blocked(x, height - 1));
+                    }
+                    for y in 0..height {
+                        assert!(map.is_blocked(0, y) && map.is_blocked(width - 1, y));
+                    }
+                }
+            }
+        }
+    }
+
+    fn default_config() -> Config {
+        Config::from_args(std::iter::empty()).unwrap()
+    }
+
+    #[test]
+    fn every_room_center_is_reachable_floor() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        let config = default_config();
+        let mut merged_levels = 0;
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (map, start, rooms) = make_rooms(&layout, &config, &mut rng);
+            let reachable = reachable_tiles(&map, start);
+            assert!(all_reachable(&map, &reachable), "seed {}", seed);
+            for room in &rooms {
+                let (x, y) = room.center();
+                assert_eq!(map[x as usize][y as usize].terrain, Terrain::Floor);
+                assert!(
+                    reachable[x as usize][y as usize],
+                    "seed {} {:?}",
+                    seed, room
+                );
+            }
+            if rooms
+                .iter()
+                .enumerate()
+                .any(|(i, a)| rooms[..i].iter().any(|b| a.intersects_with(b)))
+            {
+                merged_levels += 1;
+            }
+        }
+        // make sure merged rooms were actually covered
+        assert!(merged_levels > 0);
+    }
+
+    // A map from a dump, with every tile explored, for the pathfinding tests.
+    fn fixture(rows: &[&str], player: (i32, i32)) -> Map {
+        let text = format!(
+            "seed=0 generator=rooms depth=1\n{}\n\nobject {} {} player\n",
+            rows.join("\n"),
+            player.0,
+            player.1
+        );
+        let level = map_from_dump(&text).unwrap();
+        assert_eq!(level.start, player);
+        let mut map = level.map;
+        for tile in map.iter_mut().flatten() {
+            tile.explored = true;
+        }
+        map
+    }
+
+    #[test]
+    fn explored_path_goes_around_walls_and_water() {
+        let map = fixture(
+            &[
+                "#########",
+                "#...#...#",
+                "#.#.~.#.#",
+                "#.#...#.#",
+                "#########",
+            ],
+            (1, 1),
+        );
+        let path = explored_path(&map, (1, 1), (7, 1));
+        assert_eq!(path.last(), Some(&(7, 1)));
+        assert!(path.iter().all(|&(x, y)| !map.is_blocked(x, y)));
+        // the wall at (4, 1) and the water below it send the path down and around through (4, 3)
+        assert_eq!(path.len(), 10);
+        assert!(path.contains(&(4, 3)) && !path.contains(&(4, 2)));
+    }
+
+    #[test]
+    fn explored_path_is_empty_when_walled_off() {
+        let map = fixture(&["#######", "#..#..#", "#######"], (1, 1));
+        assert!(explored_path(&map, (1, 1), (5, 1)).is_empty());
+    }
+
+    #[test]
+    fn map_dumps_round_trip_without_loss() {
+        let rows = ["#####", "#.=~#", "#####"];
+        let text = format!("seed=7 generator=rooms depth=1\n{}\n", rows.join("\n"));
+        let map = map_from_dump(&format!("{}\nobject 1 1 player\n", text))
+            .unwrap()
+            .map;
+        // the player stands on a bridge and the stranger in rock, and both tiles survive
+        let player = Object::new(2, 1, '@', "player", colors::WHITE);
+        let stranger = Object::new(0, 0, '@', "stranger", colors::WHITE);
+        let dump = dump_map(&map, 7, 3, Generator::Rooms, &[&player, &stranger]);
+        let level = map_from_dump(&dump).unwrap();
+        assert_eq!(level.start, (2, 1));
+        assert_eq!(level.objects, vec![("stranger".to_string(), (0, 0))]);
+        assert_eq!(level.depth, 3);
+        let loaded = level.map;
+        assert_eq!(loaded[2][1].terrain, Terrain::Bridge);
+        assert_eq!(loaded[0][0].terrain, Terrain::Wall);
+        assert_eq!(
+            dump_map(&loaded, 7, 3, Generator::Rooms, &[&player, &stranger]),
+            dump
+        );
+    }
+
+    #[test]
+    fn map_dumps_without_a_player_are_rejected() {
+        let text = "seed=7 generator=rooms depth=1\n###\n#.#\n###\n";
+        assert!(map_from_dump(text).is_err());
+        assert!(map_from_dump(&format!("{}\nobject 9 9 player\n", text)).is_err());
+        // nor are dumps without a depth in the header
+        let headless = "seed=7 generator=rooms\n###\n#.#\n###\n\nobject 1 1 player\n";
+        assert!(map_from_dump(headless).is_err());
+    }
+
+    #[test]
+    fn caves_start_on_floor_and_are_connected() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (map, start) = make_caves(&layout, &mut rng);
+            assert_eq!(
+                map[start.0 as usize][start.1 as usize].terrain,
+                Terrain::Floor
+            );
+            assert!(
+                all_reachable(&map, &reachable_tiles(&map, start)),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn mazes_have_no_unreachable_floor() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (map, start) = make_maze(&layout, &mut rng);
+            assert!(!map.is_blocked(start.0, start.1));
+            assert!(
+                all_reachable(&map, &reachable_tiles(&map, start)),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    // Floor tiles dug between `rooms` when each room after the first is joined to the one
+    // `link` picks, not counting the rooms themselves.
+    fn corridor_tiles(
+        layout: &Layout,
+        rooms: &[Rect],
+        link: impl Fn(usize) -> usize,
+        rng: &mut StdRng,
+    ) -> usize {
+        let mut map = solid_map(layout.map_width, layout.map_height);
+        for i in 1..rooms.len() {
+            let (from, to) = (rooms[link(i)].center(), rooms[i].center());
+            carve_corridor(from, to, CorridorStyle::Narrow, &mut map, rng);
+        }
+        let in_room = |x: i32, y: i32| {
+            rooms
+                .iter()
+                .any(|room| x > room.x1 && x < room.x2 && y > room.y1 && y < room.y2)
+        };
+        (0..layout.map_width)
+            .flat_map(|x| (0..layout.map_height).map(move |y| (x, y)))
+            .filter(|&(x, y)| !map.is_blocked(x, y) && !in_room(x, y))
+            .count()
+    }
+
+    #[test]
+    fn linking_the_nearest_room_digs_less_corridor() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        let config = default_config();
+        let (mut nearest_total, mut sequential_total) = (0, 0);
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let (_, _, rooms) = make_rooms(&layout, &config, &mut rng);
+            nearest_total += corridor_tiles(
+                &layout,
+                &rooms,
+                |i| nearest_room(&rooms[..i], rooms[i].center()),
+                &mut StdRng::seed_from_u64(seed),
+            );
+            // the old behaviour: each room joined to the one placed before it
+            sequential_total +=
+                corridor_tiles(&layout, &rooms, |i| i - 1, &mut StdRng::seed_from_u64(seed));
+        }
+        assert!(
+            nearest_total < sequential_total,
+            "nearest {} vs sequential {} corridor tiles",
+            nearest_total,
+            sequential_total
+        );
+    }
+
+    #[test]
+    fn generate_map_gives_up_when_no_room_fits() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        let unplayable = MapGenError::NoPlayableMap {
+            generator: Generator::Rooms,
+            attempts: MAP_GEN_ATTEMPTS,
+        };
+        // settings Config::validate would reject: rooms larger than the map, or none at all
+        let mut huge_rooms = default_config();
+        huge_rooms.room_min_size = 500;
+        huge_rooms.room_max_size = 500;
+        let mut no_rooms = default_config();
+        no_rooms.max_rooms = 0;
+        for config in &[huge_rooms, no_rooms] {
+            let mut rng = StdRng::seed_from_u64(3);
+            assert_eq!(
+                generate_map(&layout, config, &mut rng).err(),
+                Some(unplayable)
+            );
+        }
+        assert_eq!(
+            unplayable.to_string(),
+            "Could not generate a playable rooms map in 10 attempts"
+        );
+    }
+
+    fn test_game(map: Map, objects: &mut Objects) -> Game {
+        let player = objects.insert(Object::new(1, 1, '@', "player", colors::WHITE));
+        Game {
+            level: LevelMetadata::survey(&map),
+            map,
+            seed: 0,
+            depth: START_DEPTH,
+            player,
+            ghosts: HashMap::new(),
+            turn: 0,
+            messages: Messages::new(),
+            travel: VecDeque::new(),
+            fov_dirty: true,
+        }
+    }
+
+    #[test]
+    fn only_creatures_coming_into_view_flush_input() {
+        let map = fixture(
+            &["#########", "#...#...#", "#...#...#", "#########"],
+            (1, 1),
+        );
+        let mut objects = Objects::new();
+        let mut game = test_game(map, &mut objects);
+        let stranger = objects.insert(Object::new(2, 2, '@', "stranger", colors::WHITE));
+        let mut fov = initialise_fov(&game.map);
+
+        // first sighting
+        fov.compute_fov(1, 1, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+        assert!(update_ghosts(&mut game, &objects, &fov));
+        // still in view on the next turn
+        game.turn += 1;
+        assert!(!update_ghosts(&mut game, &objects, &fov));
+        // behind the wall for a turn, then seen again
+        objects[stranger].x = 6;
+        game.turn += 1;
+        assert!(!update_ghosts(&mut game, &objects, &fov));
+        game.turn += 1;
+        objects[stranger].x = 2;
+        assert!(update_ghosts(&mut game, &objects, &fov));
+    }
+
+    #[test]
+    fn flushing_input_drops_queued_keys_and_the_walk() {
+        let map = fixture(&["#####", "#...#", "#####"], (1, 1));
+        let mut objects = Objects::new();
+        let mut game = test_game(map, &mut objects);
+        let mut up = Key::default();
+        up.code = KeyCode::Up;
+        let mut key_queue: VecDeque<Key> = vec![up, up].into_iter().collect();
+        game.travel = vec![(2, 1), (3, 1)].into_iter().collect();
+        flush_input(&mut key_queue, &mut game);
+        assert!(key_queue.is_empty());
+        assert!(game.travel.is_empty());
+    }
+
+    fn key(code: KeyCode) -> Key {
+        let mut key = Key::default();
+        key.code = code;
+        key
+    }
+
+    #[test]
+    fn queued_keys_are_capped_but_escape_gets_through() {
+        let mut mouse = Mouse::default();
+        let mut key_queue = VecDeque::new();
+        let events = vec![Event::Key(key(KeyCode::Up)); INPUT_QUEUE_LIMIT + 3];
+        queue_events(events, &mut mouse, &mut key_queue);
+        assert_eq!(key_queue.len(), INPUT_QUEUE_LIMIT);
+        queue_events(
+            vec![Event::Key(key(KeyCode::Escape))],
+            &mut mouse,
+            &mut key_queue,
+        );
+        assert_eq!(key_queue.len(), INPUT_QUEUE_LIMIT + 1);
+        assert_eq!(key_queue.back().unwrap().code, KeyCode::Escape);
+    }
+
+    #[test]
+    fn simultaneous_input_is_handled_in_order_one_key_per_frame() {
+        let mut mouse = Mouse::default();
+        let mut key_queue = VecDeque::new();
+        let click = Mouse {
+            lbutton_pressed: true,
+            ..Mouse::default()
+        };
+        let events = vec![
+            Event::Key(key(KeyCode::Up)),
+            Event::Mouse(click),
+            Event::Key(key(KeyCode::Right)),
+            Event::Mouse(Mouse::default()),
+            Event::Key(key(KeyCode::Down)),
+        ];
+        queue_events(events, &mut mouse, &mut key_queue);
+        // a click survives the mouse moving on in the same frame
+        assert!(mouse.lbutton_pressed);
+        assert_eq!(next_key(&mut key_queue).code, KeyCode::Up);
+        assert_eq!(next_key(&mut key_queue).code, KeyCode::Right);
+        assert_eq!(next_key(&mut key_queue).code, KeyCode::Down);
+        assert_eq!(next_key(&mut key_queue).code, KeyCode::NoKey);
+    }
+
+    #[test]
+    fn sighting_something_flushes_keys_queued_in_the_same_frame() {
+        let map = fixture(&["#######", "#.....#", "#######"], (1, 1));
+        let mut objects = Objects::new();
+        let mut game = test_game(map, &mut objects);
+        let stranger = objects.insert(Object::new(5, 1, '@', "stranger", colors::WHITE));
+        let mut fov = initialise_fov(&game.map);
+        let mut mouse = Mouse::default();
+        let mut key_queue = VecDeque::new();
+        let events = vec![Event::Key(key(KeyCode::Right)); 3];
+        queue_events(events, &mut mouse, &mut key_queue);
+        fov.compute_fov(1, 1, 10, true, FovAlgorithm::Basic);
+        if update_ghosts(&mut game, &objects, &fov) {
+            flush_input(&mut key_queue, &mut game);
+        }
+        assert!(game.ghosts.contains_key(&stranger));
+        assert_eq!(next_key(&mut key_queue).code, KeyCode::NoKey);
+    }
+
+    #[test]
+    fn describe_player_tile_summarizes_names_that_dont_fit() {
+        let mut objects = Objects::new();
+        let game = test_game(vec![vec![Tile::empty(); 3]; 3], &mut objects);
+        let mut text = String::from("left over");
+        assert!(!describe_player_tile(&objects, game.player, 80, &mut text));
+        assert!(text.is_empty());
+
+        for name in &["potion", "scroll", "dagger"] {
+            objects.insert(Object::new(1, 1, '!', name, colors::WHITE));
+        }
+        assert!(describe_player_tile(&objects, game.player, 80, &mut text));
+        assert_eq!(text, "You see potion, scroll, dagger here.");
+        assert!(describe_player_tile(&objects, game.player, 35, &mut text));
+        assert_eq!(text, "You see potion ...and 2 more here.");
+        assert!(describe_player_tile(&objects, game.player, 10, &mut text));
+        assert_eq!(text, "You see po");
+    }
+
+    #[test]
+    fn panel_text_reuses_its_string() {
+        let mut objects = Objects::new();
+        let game = test_game(vec![vec![Tile::empty(); 3]; 3], &mut objects);
+        let mut text = String::with_capacity(80);
+        let start = alloc_count::allocations();
+        describe_player_tile(&objects, game.player, 80, &mut text);
+        text.clear();
+        let _ = write!(text, "Turn: {}", game.turn);
+        assert_eq!(alloc_count::allocations() - start, 0);
+    }
+
+    #[test]
+    fn tile_colors_follow_the_palette() {
+        let palette = PALETTES[0].1;
+        let map = fixture(&["#~=."], (3, 0));
+        let colors = tile_colors(&map, &palette);
+        assert_eq!(colors[0][0], (palette.dark_wall, palette.light_wall));
+        assert_eq!(colors[1][0], (palette.dark_water, palette.light_water));
+        assert_eq!(colors[2][0], (palette.dark_bridge, palette.light_bridge));
+        assert_eq!(colors[3][0], (palette.dark_ground, palette.light_ground));
+    }
+
+    // A map of the given size that is open floor inside a solid outer wall.
+    fn open_map(width: i32, height: i32) -> Map {
+        let mut map = solid_map(width, height);
+        for x in 1..width - 1 {
+            for y in 1..height - 1 {
+                map.set_tile(x, y, Tile::empty());
+            }
+        }
+        map
+    }
+
+    // Every tile of a small map: the edges, corners and interior.
+    fn all_points(width: i32, height: i32) -> Vec<(i32, i32)> {
+        (0..width)
+            .flat_map(|x| (0..height).map(move |y| (x, y)))
+            .collect()
+    }
+
+    #[test]
+    fn tiles_can_be_set_up_to_every_edge() {
+        for &(width, height) in &[(1, 1), (2, 5), (5, 2), (6, 6)] {
+            let mut map = solid_map(width, height);
+            for (x, y) in all_points(width, height) {
+                map.set_tile(x, y, Tile::empty());
+                assert!(!map.is_blocked(x, y));
+            }
+            // everything off the map reads as blocked
+            for i in -2..width.max(height) + 2 {
+                for &(x, y) in &[(i, -1), (i, height), (-1, i), (width, i)] {
+                    assert!(map.tile(x, y).is_none() && map.is_blocked(x, y));
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "off the map")]
+    fn setting_a_tile_off_the_map_is_caught_in_debug_builds() {
+        let mut map = solid_map(4, 4);
+        map.set_tile(4, 0, Tile::empty());
+    }
+
+    #[test]
+    fn corridors_between_any_two_tiles_of_small_maps() {
+        for &(width, height) in &[(3, 3), (4, 3), (3, 5), (5, 4), (7, 6)] {
+            let points = all_points(width, height);
+            for &style in &[
+                CorridorStyle::Narrow,
+                CorridorStyle::Wide,
+                CorridorStyle::Stepped,
+            ] {
+                let mut rng = StdRng::seed_from_u64(width as u64 * 10 + height as u64);
+                for &from in &points {
+                    for &to in &points {
+                        // set_tile's debug assertion catches any tile dug off the map
+                        let mut map = solid_map(width, height);
+                        carve_corridor(from, to, style, &mut map, &mut rng);
+                        assert!(connected(&map, from, to), "{:?} {:?}", from, to);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rivers_on_small_maps() {
+        for width in 3..9 {
+            for height in 3..9 {
+                for seed in 0..10 {
+                    let mut rng = StdRng::seed_from_u64(seed);
+                    let mut map = open_map(width, height);
+                    let start = (rng.gen_range(1, width - 1), rng.gen_range(1, height - 1));
+                    add_river(&mut map, start, &mut rng);
+                    let size = (map.len() as i32, map[0].len() as i32);
+                    assert_eq!(size, (width, height));
+                    assert!(all_reachable(&map, &reachable_tiles(&map, start)));
+                    for (x, y) in all_points(width, height) {
+                        let edge = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+                        if edge {
+                            assert_eq!(map[x as usize][y as usize].terrain, Terrain::Wall);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn explored_path_to_the_map_edge() {
+        let map = fixture(&["....", "....", "...."], (0, 0));
+        for (x, y) in all_points(4, 3) {
+            let path = explored_path(&map, (0, 0), (x, y));
+            if (x, y) != (0, 0) {
+                assert_eq!(path.len() as i32, x + y);
+            }
+        }
+    }
+
+    fn rooms_config(min_size: i32, max_size: i32, max_rooms: i32) -> Config {
+        let mut config = default_config();
+        config.room_min_size = min_size;
+        config.room_max_size = max_size;
+        config.max_rooms = max_rooms;
+        config
+    }
+
+    #[test]
+    fn validate_rejects_room_settings_that_cant_work() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        assert_eq!(rooms_config(6, 10, 30).validate(&layout), Ok(()));
+        assert_eq!(
+            rooms_config(2, 10, 30).validate(&layout),
+            Err("--room-min-size is 2, it must be at least 3".to_string())
+        );
+        assert_eq!(
+            rooms_config(12, 10, 30).validate(&layout),
+            Err("--room-min-size (12) is larger than --room-max-size (10)".to_string())
+        );
+        let too_big = layout.map_height;
+        assert_eq!(
+            rooms_config(6, too_big, 30).validate(&layout),
+            Err(format!(
+                "--room-max-size ({}) doesn't fit in the {}x{} map",
+                too_big, layout.map_width, layout.map_height
+            ))
+        );
+        assert_eq!(
+            rooms_config(6, 10, 0).validate(&layout),
+            Err("--max-rooms is 0, it must be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn make_map_survives_settings_validate_would_reject() {
+        let layout = Layout::new(MIN_SCREEN_WIDTH, MIN_SCREEN_HEIGHT).unwrap();
+        let configs = [
+            rooms_config(12, 4, 30),
+            rooms_config(layout.map_width, layout.map_width, 30),
+            rooms_config(layout.map_height, layout.map_height, 30),
+            rooms_config(1000, 1000, 5),
+            rooms_config(-5, 2, 30),
+            rooms_config(0, 0, 30),
+            rooms_config(6, 10, 0),
+            rooms_config(6, 10, -3),
+        ];
+        for config in &configs {
+            assert!(config.validate(&layout).is_err());
+            for seed in 0..10 {
+                let mut rng = StdRng::seed_from_u64(seed);
+                let (map, _) = make_map(&layout, config, &mut rng);
+                assert_eq!(map.len() as i32, layout.map_width);
+            }
+        }
+    }
+
+    #[test]
+    fn the_minimap_fits_in_the_panel_beside_the_messages() {
+        for &(width, height) in &[
+            (MIN_SCREEN_WIDTH, MIN_SCREEN_HEIGHT),
+            (SCREEN_WIDTH, SCREEN_HEIGHT),
+        ] {
+            let layout = Layout::new(width, height).unwrap();
+            assert_eq!(layout.map_width, width);
+            assert_eq!(layout.minimap_x + MINIMAP_SIZE, width);
+            // room for at least a short message between the HUD and the minimap
+            assert!(layout.minimap_x - 1 - MSG_X >= 20);
+        }
+    }
+
+    #[test]
+    fn ghosts_fade_toward_the_palette_background() {
+        let mut palette = PALETTES[0].1;
+        palette.background = Color::new(200, 200, 200);
+        let ghost = Ghost {
+            x: 0,
+            y: 0,
+            char: 'o',
+            color: Color::new(0, 100, 200),
+            turn: 0,
+        };
+        let faded = ghost_color(&ghost, &palette);
+        assert_eq!((faded.r, faded.g, faded.b), (120, 160, 200));
+    }
+
+    fn named(name: &str) -> Object {
+        Object::new(0, 0, '?', name, colors::WHITE)
+    }
+
+    #[test]
+    fn removed_objects_stop_resolving() {
+        let mut objects = Objects::new();
+        let orc = objects.insert(named("orc"));
+        let troll = objects.insert(named("troll"));
+        assert_eq!(
+            objects.remove(orc).map(|object| object.name),
+            Some("orc".to_string())
+        );
+        assert!(objects.get(orc).is_none() && objects.get_mut(orc).is_none());
+        assert!(objects.remove(orc).is_none());
+        assert_eq!(objects.get(troll).unwrap().name, "troll");
+        assert_eq!(
+            objects.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            vec![troll]
+        );
+    }
+
+    #[test]
+    fn reused_slots_dont_resolve_stale_ids() {
+        let mut objects = Objects::new();
+        let orc = objects.insert(named("orc"));
+        objects.remove(orc);
+        let potion = objects.insert(named("potion"));
+        // the potion took the orc's slot, but the orc's id must not find it
+        assert_ne!(orc, potion);
+        assert!(objects.get(orc).is_none());
+        assert!(objects.remove(orc).is_none());
+        assert_eq!(objects.get(potion).unwrap().name, "potion");
+        assert_eq!(objects.iter().count(), 1);
+    }
+
+    #[test]
+    fn get_two_mut_needs_two_different_live_objects() {
+        let mut objects = Objects::new();
+        let orc = objects.insert(named("orc"));
+        let troll = objects.insert(named("troll"));
+        assert!(objects.get_two_mut(orc, orc).is_none());
+
+        let (first, second) = objects.get_two_mut(troll, orc).unwrap();
+        assert_eq!(
+            (first.name.as_str(), second.name.as_str()),
+            ("troll", "orc")
+        );
+        first.x = 5;
+        second.x = 7;
+        assert_eq!((objects[troll].x, objects[orc].x), (5, 7));
+
+        objects.remove(orc);
+        let potion = objects.insert(named("potion"));
+        assert!(objects.get_two_mut(troll, orc).is_none());
+        assert!(objects.get_two_mut(orc, potion).is_none());
+        assert!(objects.get_two_mut(potion, troll).is_some());
+    }
+
+    #[test]
+    fn map_dumps_may_have_blank_lines_among_the_objects() {
+        let text = "seed=7 generator=rooms depth=1\n###\n#.#\n###\n\n\nobject 1 1 player\n\n\n";
+        assert_eq!(map_from_dump(text).unwrap().start, (1, 1));
+    }
+
+    #[test]
+    fn parse_color_takes_six_hex_digits_only() {
+        assert_eq!(parse_color("#ff8000"), Ok(Color::new(255, 128, 0)));
+        assert_eq!(parse_color("#0A0b0C"), Ok(Color::new(10, 11, 12)));
+        for bad in &[
+            "ff8000", "#ff800", "#ff80000", "#+1+2+3", "#gg0000", "#ff80é", "",
+        ] {
+            assert!(parse_color(bad).is_err(), "{} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn set_entity_color_knows_the_player_and_the_stranger() {
+        let mut palette = Palette::by_name("classic").unwrap();
+        palette.set_entity_color("player", colors::RED).unwrap();
+        palette.set_entity_color("stranger", colors::GREEN).unwrap();
+        assert_eq!((palette.player, palette.npc), (colors::RED, colors::GREEN));
+        let error = palette.set_entity_color("orc", colors::RED).unwrap_err();
+        assert_eq!(
+            error,
+            "Unknown entity orc, expected one of: player, stranger"
+        );
+        assert_eq!(palette.player, colors::RED);
+    }
+
+    #[test]
+    fn low_contrast_entities_are_close_to_both_grounds() {
+        let mut palette = Palette::by_name("classic").unwrap();
+        assert!(palette.low_contrast_entities(MIN_CONTRAST).is_empty());
+        // close to the lit ground only is still readable in the dark
+        palette.player = palette.light_ground;
+        assert!(palette.low_contrast_entities(MIN_CONTRAST).is_empty());
+        palette.dark_ground = palette.light_ground;
+        assert_eq!(palette.low_contrast_entities(MIN_CONTRAST), vec!["player"]);
+    }
+
+    #[test]
+    fn moving_marks_the_fov_dirty_and_a_recompute_clears_it() {
+        let map = fixture(&["#####", "#...#", "#####"], (1, 1));
+        let mut objects = Objects::new();
+        let mut game = test_game(map, &mut objects);
+        let mut fov = initialise_fov(&game.map);
+        // a freshly loaded map starts dirty
+        assert!(game.fov_dirty);
+        assert!(update_fov(&mut fov, &mut game, &objects));
+        assert!(!game.fov_dirty);
+        assert!(fov.is_in_fov(3, 1));
+        assert!(!update_fov(&mut fov, &mut game, &objects));
+
+        // bumping into a wall changes nothing
+        move_player(&mut objects[game.player], 0, -1, &mut game);
+        assert!(!game.fov_dirty);
+        move_player(&mut objects[game.player], 1, 0, &mut game);
+        assert!(game.fov_dirty);
+        assert!(update_fov(&mut fov, &mut game, &objects));
+        assert!(!game.fov_dirty);
+    }
+
+    #[test]
+    fn a_dumped_map_sees_the_same_as_the_generated_one() {
+        let layout = Layout::new(SCREEN_WIDTH, SCREEN_HEIGHT).unwrap();
+        for generator in &[Generator::Rooms, Generator::Caves, Generator::Maze] {
+            let mut config = default_config();
+            config.generator = *generator;
+            let mut rng = StdRng::seed_from_u64(3);
+            let (map, start) = generate_map(&layout, &config, &mut rng).unwrap();
+            let player = Object::new(start.0, start.1, '@', "player", colors::WHITE);
+            let loaded = map_from_dump(&dump_map(&map, 3, START_DEPTH, *generator, &[&player]));
+            let loaded = loaded.unwrap();
+            assert_eq!(loaded.start, start);
+
+            let mut generated_fov = initialise_fov(&map);
+            let mut loaded_fov = initialise_fov(&loaded.map);
+            for fov in &mut [&mut generated_fov, &mut loaded_fov] {
+                fov.compute_fov(start.0, start.1, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO);
+            }
+            for (x, y) in all_points(layout.map_width, layout.map_height) {
+                assert_eq!(
+                    generated_fov.is_in_fov(x, y),
+                    loaded_fov.is_in_fov(x, y),
+                    "{:?} at ({}, {})",
+                    generator,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn screenshots_and_bug_reports_leave_the_overlay_out() {
+        let mut config = default_config();
+        let mut view = View {
+            show_overview: false,
+            show_debug_overlay: true,
+            screenshot_requested: false,
+            bug_report_requested: false,
+            preview_key: None,
+            path_preview: vec![],
+        };
+        assert!(view.shows_debug_overlay(&config));
+        view.screenshot_requested = true;
+        assert!(!view.shows_debug_overlay(&config));
+        view.screenshot_requested = false;
+        view.bug_report_requested = true;
+        assert!(!view.shows_debug_overlay(&config));
+        config.screenshot_overlay = true;
+        assert!(view.shows_debug_overlay(&config));
+        view.show_debug_overlay = false;
+        assert!(!view.shows_debug_overlay(&config));
+    }
+
+    // Whether the rooms joined by `links` and `pairs` contain a cycle, by union-find.
+    fn has_cycle(rooms: usize, links: &[Option<usize>], pairs: &[(usize, usize)]) -> bool {
+        let mut parent: Vec<usize> = (0..rooms).collect();
+        fn root(parent: &mut [usize], mut room: usize) -> usize {
+            while parent[room] != room {
+                room = parent[room];
+            }
+            room
+        }
+        let edges = links
+            .iter()
+            .enumerate()
+            .filter_map(|(room, link)| link.map(|other| (room, other)))
+            .chain(pairs.iter().cloned());
+        for (a, b) in edges {
+            let (root_a, root_b) = (root(&mut parent, a), root(&mut parent, b));
+            if root_a == root_b {
+                return true;
+            }
+            parent[root_a] = root_b;
+        }
+        false
+    }
+
+    #[test]
+    fn loop_corridors_close_a_cycle_in_the_room_graph() {
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for rooms in 3..12 {
+                // a random tree, like make_map builds: each room links to an earlier one
+                let links: Vec<Option<usize>> = (0..rooms)
+                    .map(|room| match room {
+                        0 => None,
+                        _ => Some(rng.gen_range(0, room as u32) as usize),
+                    })
+                    .collect();
+                assert!(!has_cycle(rooms, &links, &[]));
+                for count in 1..=LOOP_CORRIDORS {
+                    let pairs = loop_corridor_pairs(&links, count, &mut rng);
+                    assert!(!pairs.is_empty() && pairs.len() <= count);
+                    assert!(has_cycle(rooms, &links, &pairs), "seed {}", seed);
+                    for (i, &(a, b)) in pairs.iter().enumerate() {
+                        assert!(links[a] != Some(b) && links[b] != Some(a));
+                        assert!(!pairs[..i].contains(&(a, b)), "{:?} drawn twice", (a, b));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn loop_corridors_can_be_turned_off() {
+        let links = [None, Some(0), Some(1), Some(1)];
+        let mut rng = StdRng::seed_from_u64(1);
+        assert!(loop_corridor_pairs(&links, 0, &mut rng).is_empty());
+        // only three pairs aren't linked directly, so asking for more gives just those
+        assert_eq!(loop_corridor_pairs(&links, 10, &mut rng).len(), 3);
     }
 }
@@ -0,0 +1,146 @@
+// Input traces for bug reports: the seed and settings a game was started with, every move the
+// player made and periodic checksums of the game state. Replaying the moves on a map generated
+// from the same seed must reproduce the same checksums.
+//
+// The file is plain text, one entry per line:
+//
+//     seed 1234 config 8c5b0e1f3a2d4c67
+//     move 1 1 0
+//     check 10 5f1e2d3c4b5a6978
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// 64-bit FNV-1a, a cheap hash that is stable across runs and platforms.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Entry {
+    // The move the player made on this turn; (0, 0) when they bumped into something.
+    Move { turn: u32, dx: i32, dy: i32 },
+    // The state checksum after this turn.
+    Check { turn: u32, checksum: u64 },
+}
+
+pub struct Trace {
+    pub seed: u64,
+    pub config: u64,
+    pub entries: Vec<Entry>,
+}
+
+impl Trace {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+        let mut lines = text.lines().enumerate();
+        let invalid = |number: usize| format!("line {} is not a valid trace entry", number + 1);
+
+        let (number, header) = lines.next().ok_or("the trace is empty")?;
+        let fields: Vec<&str> = header.split(' ').collect();
+        let (seed, config) = match fields.as_slice() {
+            ["seed", seed, "config", config] => (
+                seed.parse().map_err(|_| invalid(number))?,
+                u64::from_str_radix(config, 16).map_err(|_| invalid(number))?,
+            ),
+            _ => return Err(invalid(number)),
+        };
+
+        let mut entries = vec![];
+        for (number, line) in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let entry = match fields.as_slice() {
+                ["move", turn, dx, dy] => Entry::Move {
+                    turn: turn.parse().map_err(|_| invalid(number))?,
+                    dx: dx.parse().map_err(|_| invalid(number))?,
+                    dy: dy.parse().map_err(|_| invalid(number))?,
+                },
+                ["check", turn, checksum] => Entry::Check {
+                    turn: turn.parse().map_err(|_| invalid(number))?,
+                    checksum: u64::from_str_radix(checksum, 16).map_err(|_| invalid(number))?,
+                },
+                _ => return Err(invalid(number)),
+            };
+            entries.push(entry);
+        }
+        Ok(Trace {
+            seed,
+            config,
+            entries,
+        })
+    }
+}
+
+// Writes a trace as the game is played. Moves are buffered and written out with each checksum,
+// so a crash loses at most the turns since the last one; call `flush` before copying the file.
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, seed: u64, config: u64) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "seed {} config {:016x}", seed, config)?;
+        Ok(Recorder { file })
+    }
+
+    pub fn record(&mut self, entry: Entry) -> io::Result<()> {
+        match entry {
+            Entry::Move { turn, dx, dy } => writeln!(self.file, "move {} {} {}", turn, dx, dy),
+            Entry::Check { turn, checksum } => {
+                writeln!(self.file, "check {} {:016x}", turn, checksum)?;
+                self.file.flush()
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_reach_the_file_without_a_flush() {
+        let path = std::env::temp_dir().join(format!("trace-{}.txt", std::process::id()));
+        let mut recorder = Recorder::create(&path, 1234, 0xabc).unwrap();
+        recorder
+            .record(Entry::Move {
+                turn: 1,
+                dx: 1,
+                dy: 0,
+            })
+            .unwrap();
+        recorder
+            .record(Entry::Check {
+                turn: 1,
+                checksum: 0xdead_beef,
+            })
+            .unwrap();
+        // the recorder is still open, as if the game had crashed here
+        let trace = Trace::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((trace.seed, trace.config), (1234, 0xabc));
+        assert_eq!(
+            trace.entries,
+            vec![
+                Entry::Move {
+                    turn: 1,
+                    dx: 1,
+                    dy: 0
+                },
+                Entry::Check {
+                    turn: 1,
+                    checksum: 0xdead_beef
+                },
+            ]
+        );
+        drop(recorder);
+    }
+}